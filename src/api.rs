@@ -1,30 +1,60 @@
 use crate::{
-    chain::{get_timestamp, Block, Chain},
+    chain::{Block, Chain, Reorg},
+    hash::BlockHash,
+    mempool::Mempool,
+    merkle::{verify_proof, MerkleProof},
+    miner::{new_block_template, seal_block, submit_block, MinerConfig, MinerStats},
     net::{P2PMessage, ReceiveHandlers, TransmitHandlers},
+    transaction::{generate_keypair, Transaction},
+    utxo::{OutPoint, TxOutput},
 };
 use actix_web::{web, HttpResponse, Responder};
-use libp2p::{Multiaddr, PeerId};
+use libp2p::PeerId;
 use serde::{Deserialize, Serialize};
+use std::sync::atomic::AtomicBool;
 use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use tokio::sync::watch;
 
 pub struct ApiState {
     pub chains: Arc<Mutex<Chain>>,
+    pub mempool: Arc<Mutex<Mempool>>,
     pub transmit_handlers: TransmitHandlers,
     pub receive_handlers: ReceiveHandlers,
+    pub miner: MinerConfig,
+    pub miner_stats: MinerStats,
+    /// Hash of our current tip, watched by the miner to abandon stale work.
+    pub tip_tx: watch::Sender<BlockHash>,
 }
 
 impl ApiState {
     pub fn new(
         chains: Arc<Mutex<Chain>>,
+        mempool: Arc<Mutex<Mempool>>,
         transmit_handlers: TransmitHandlers,
         receive_handlers: ReceiveHandlers,
+        miner: MinerConfig,
     ) -> Self {
         Self {
             chains,
+            mempool,
             transmit_handlers,
             receive_handlers,
+            miner,
+            miner_stats: MinerStats::default(),
+            tip_tx: watch::Sender::new(BlockHash::default()),
         }
     }
+
+    /// Brings the mempool in line with the new tip of `chains` and tells the
+    /// miner about it. Must be called with the chain lock held.
+    pub fn on_new_tip(&self, chains: &Chain, reorg: &Reorg) {
+        self.mempool
+            .lock()
+            .unwrap()
+            .apply_reorg(reorg, chains.state());
+        let tip = chains.get_latest_block().unwrap().header.hash;
+        self.tip_tx.send_replace(tip);
+    }
 }
 fn handle_poisoned_lock<T>(err: PoisonError<MutexGuard<T>>) -> HttpResponse {
     // Handle the poisoned lock scenario
@@ -34,16 +64,28 @@ fn handle_poisoned_lock<T>(err: PoisonError<MutexGuard<T>>) -> HttpResponse {
 
 #[derive(Serialize, Deserialize)]
 struct GetBlocksSchema {
+    version: u32,
     index: usize,
     timestamp: u64,
-    data: String,
+    merkle_root: String,
+    transactions: Vec<Transaction>,
+    difficulty: u32,
+    nonce: u64,
+    hash: BlockHash,
+    signature: String,
 }
 impl From<&Block> for GetBlocksSchema {
     fn from(value: &Block) -> Self {
         GetBlocksSchema {
-            index: value.index,
-            timestamp: value.timestamp,
-            data: value.data.clone(),
+            version: value.header.version,
+            index: value.header.index,
+            timestamp: value.header.timestamp,
+            merkle_root: value.header.merkle_root.clone(),
+            transactions: value.transactions.clone(),
+            difficulty: value.header.difficulty,
+            nonce: value.header.nonce,
+            hash: value.header.hash,
+            signature: value.header.signature.clone(),
         }
     }
 }
@@ -57,12 +99,12 @@ struct GetChainSchema {
 impl From<&Chain> for GetChainSchema {
     fn from(value: &Chain) -> Self {
         let chains: Vec<GetBlocksSchema> = value
-            .chains
+            .get_blocks()
             .iter()
-            .map(|b| Into::<GetBlocksSchema>::into(b))
+            .map(Into::<GetBlocksSchema>::into)
             .collect();
         GetChainSchema {
-            next_index: value.next_index,
+            next_index: value.next_index(),
             chains,
         }
     }
@@ -75,37 +117,105 @@ pub async fn api_blocks(state: web::Data<ApiState>) -> impl Responder {
     }
 }
 
+pub async fn api_merkle_proof(
+    path: web::Path<(usize, usize)>,
+    state: web::Data<ApiState>,
+) -> impl Responder {
+    let (block_index, item_index) = path.into_inner();
+    match state.chains.lock() {
+        Ok(chains) => match chains.merkle_proof(block_index, item_index) {
+            Some(proof) => HttpResponse::Ok().json(proof),
+            None => HttpResponse::NotFound().body("no such block or transaction"),
+        },
+        Err(poisoned) => handle_poisoned_lock(poisoned),
+    }
+}
+
 #[derive(Deserialize)]
-pub struct MineSchema {
-    data: String,
-}
-
-pub async fn api_mine(msg: web::Json<MineSchema>, data: web::Data<ApiState>) -> impl Responder {
-    let mut chains = data.chains.lock().unwrap();
-    let last_block = chains.get_latest_block().unwrap();
-    let index = chains.next_index;
-    let previous_hash = last_block.hash.clone();
-    let timestamp = get_timestamp();
-    println!("chain is {chains:?}");
-    let new_block = Block::new(index, &previous_hash, timestamp, msg.data.as_str());
-    chains.add_block(new_block.clone());
-    println!("chain is {chains:?}");
-    if let Err(e) = data
-        .transmit_handlers
-        .swarm_tx
-        .send(P2PMessage::ResponseBlockchain(vec![new_block]))
-    {
-        log::error!("error is {e}");
+pub struct VerifyProofSchema {
+    proof: MerkleProof,
+    merkle_root: String,
+}
+
+pub async fn api_verify_proof(msg: web::Json<VerifyProofSchema>) -> impl Responder {
+    HttpResponse::Ok().json(verify_proof(&msg.proof, &msg.merkle_root))
+}
+
+pub async fn api_mine(data: web::Data<ApiState>) -> impl Responder {
+    let template = new_block_template(&data);
+    let cancel = Arc::new(AtomicBool::new(false));
+    let block = match seal_block(data.clone(), template, cancel).await {
+        Ok(Some(block)) => block,
+        Ok(None) => return HttpResponse::InternalServerError().body("mining was cancelled"),
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    match submit_block(&data, block) {
+        Ok(()) => HttpResponse::Ok().body("ok"),
+        Err(e) => HttpResponse::Conflict().body(e),
     }
+}
 
-    HttpResponse::Ok().body("ok")
+pub async fn api_hashrate(data: web::Data<ApiState>) -> impl Responder {
+    HttpResponse::Ok().json(data.miner_stats.hashrate(data.miner.threads))
+}
+
+pub async fn api_pending_transactions(data: web::Data<ApiState>) -> impl Responder {
+    match data.mempool.lock() {
+        Ok(mempool) => HttpResponse::Ok().json(mempool.pending(usize::MAX)),
+        Err(poisoned) => handle_poisoned_lock(poisoned),
+    }
 }
+
+pub async fn api_submit_transaction(
+    msg: web::Json<Transaction>,
+    data: web::Data<ApiState>,
+) -> impl Responder {
+    let transaction = msg.into_inner();
+    let chains = data.chains.lock().unwrap();
+    let added = data
+        .mempool
+        .lock()
+        .unwrap()
+        .add(transaction.clone(), chains.state());
+    match added {
+        Ok(true) => {
+            if let Err(e) = data
+                .transmit_handlers
+                .swarm_tx
+                .send(P2PMessage::NewTransaction(transaction))
+            {
+                log::error!("error is {e}");
+            }
+            HttpResponse::Ok().body("ok")
+        }
+        Ok(false) => HttpResponse::Ok().body("already pending"),
+        Err(e) => HttpResponse::BadRequest().body(e),
+    }
+}
+
+pub async fn api_account(path: web::Path<String>, data: web::Data<ApiState>) -> impl Responder {
+    match data.chains.lock() {
+        Ok(chains) => HttpResponse::Ok().json(chains.get_account(&path)),
+        Err(poisoned) => handle_poisoned_lock(poisoned),
+    }
+}
+
+pub async fn api_unspent_outputs(
+    path: web::Path<String>,
+    data: web::Data<ApiState>,
+) -> impl Responder {
+    match data.chains.lock() {
+        Ok(chains) => HttpResponse::Ok().json(chains.unspent_outputs(&path)),
+        Err(poisoned) => handle_poisoned_lock(poisoned),
+    }
+}
+
 pub async fn api_peer(data: web::Data<ApiState>) -> impl Responder {
     data.transmit_handlers
         .router_tx
-        .send(P2PMessage::QueryPeers)
+        .send((P2PMessage::QueryPeers, None))
         .unwrap();
-    let mut rx = data.receive_handlers.api_peers_rx.lock().unwrap();
+    let mut rx = data.receive_handlers.api_peers_rx.lock().await;
     let msg: Option<Vec<PeerId>> = tokio::select! {
         Some(msg) = rx.recv() => {
             match msg {
@@ -135,10 +245,60 @@ pub async fn api_add_peer(
     msg: web::Json<AddPerrSchema>,
     data: web::Data<ApiState>,
 ) -> impl Responder {
-    let _ = data
-        .transmit_handlers
+    data.transmit_handlers
         .router_tx
-        .send(P2PMessage::AddPeer(msg.peer.clone()))
+        .send((P2PMessage::AddPeer(msg.peer.clone()), None))
         .unwrap();
     HttpResponse::Ok().body("ok")
 }
+
+#[derive(Serialize)]
+struct KeypairSchema {
+    public_key: String,
+    secret_key: String,
+}
+
+pub async fn api_new_wallet() -> impl Responder {
+    let (public_key, secret_key) = generate_keypair();
+    HttpResponse::Ok().json(KeypairSchema {
+        public_key,
+        secret_key,
+    })
+}
+
+#[derive(Deserialize)]
+pub struct SignSchema {
+    secret_key: String,
+    #[serde(default)]
+    payload: String,
+    recipient: Option<String>,
+    #[serde(default)]
+    amount: u64,
+    #[serde(default)]
+    fee: u64,
+    #[serde(default)]
+    inputs: Vec<OutPoint>,
+    #[serde(default)]
+    outputs: Vec<TxOutput>,
+    #[serde(default)]
+    nonce: u64,
+}
+
+pub async fn api_sign_transaction(msg: web::Json<SignSchema>) -> impl Responder {
+    let msg = msg.into_inner();
+    let transaction = Transaction {
+        sender: String::new(),
+        payload: msg.payload,
+        recipient: msg.recipient,
+        amount: msg.amount,
+        fee: msg.fee,
+        inputs: msg.inputs,
+        outputs: msg.outputs,
+        nonce: msg.nonce,
+        signature: String::new(),
+    };
+    match transaction.sign(&msg.secret_key) {
+        Ok(transaction) => HttpResponse::Ok().json(transaction),
+        Err(e) => HttpResponse::BadRequest().body(e),
+    }
+}
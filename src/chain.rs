@@ -1,128 +1,1038 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io;
 use std::time::SystemTime;
 
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
 
+use crate::consensus::{Consensus, ConsensusMode};
+use crate::hash::{BlockHash, HashAlgorithm};
+use crate::merkle::{self, MerkleProof};
+use crate::pow::{PowFunction, PowParams};
+use crate::state::{Account, BlockUndo, LedgerMode, WorldState};
+use crate::storage::BlockStore;
+use crate::transaction::{keypair_from_hex, verify_hex_signature, Transaction};
+use crate::utxo::UnspentOutput;
+
+/// Header encoding hashed by new blocks, see [`encode_header`].
+pub const BLOCK_VERSION: u32 = 1;
+/// Original header encoding, which concatenates the fields without
+/// separators. Only accepted up to [`ChainParams::legacy_header_height`].
+pub const LEGACY_BLOCK_VERSION: u32 = 0;
+
+/// Everything about a block but its transactions: enough to check how it
+/// links to its parent and how it was sealed.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
-pub struct Block {
+pub struct BlockHeader {
+    /// Encoding of the header hashed into `hash`. Blocks predating versions
+    /// deserialize as [`LEGACY_BLOCK_VERSION`].
+    #[serde(default)]
+    pub version: u32,
     pub index: usize,
-    pub previous_hash: String,
+    pub previous_hash: BlockHash,
     pub timestamp: u64,
-    pub data: String,
-    pub hash: String,
+    /// Merkle root over the ids of the block transactions, committed to by
+    /// `hash`.
+    pub merkle_root: String,
+    pub difficulty: u32,
+    pub nonce: u64,
+    pub hash: BlockHash,
+    /// Hex encoded ed25519 signature over `hash` by the proposer, in
+    /// proof-of-authority mode.
+    #[serde(default)]
+    pub signature: String,
+}
+
+impl BlockHeader {
+    pub fn calculate_hash(&self, algorithm: HashAlgorithm) -> BlockHash {
+        algorithm.digest(header_preimage(
+            self.version,
+            self.index,
+            &self.previous_hash,
+            self.timestamp,
+            &self.merkle_root,
+            self.difficulty,
+            self.nonce,
+        ))
+    }
+
+    /// Signs `hash` with the hex encoded ed25519 `secret_key` of the proposer.
+    pub fn sign(&mut self, secret_key: &str) -> Result<(), String> {
+        let keypair = keypair_from_hex(secret_key)?;
+        self.signature = hex::encode(keypair.sign(self.hash.as_bytes()));
+        Ok(())
+    }
+
+    pub fn verify_signature(&self, public_key: &str) -> bool {
+        verify_hex_signature(public_key, self.hash.as_bytes(), &self.signature)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    pub header: BlockHeader,
+    pub transactions: Vec<Transaction>,
 }
 
 impl Block {
     pub fn _new_with_hash(
         index: usize,
-        previous_hash: &str,
+        previous_hash: BlockHash,
         timestamp: u64,
-        data: &str,
-        hash: &str,
+        transactions: &[Transaction],
+        difficulty: u32,
+        nonce: u64,
+        hash: BlockHash,
     ) -> Block {
         Block {
-            index,
-            previous_hash: previous_hash.to_string(),
-            timestamp,
-            data: data.to_string(),
-            hash: hash.to_string(),
+            header: BlockHeader {
+                version: BLOCK_VERSION,
+                index,
+                previous_hash,
+                timestamp,
+                merkle_root: calculate_merkle_root(transactions),
+                difficulty,
+                nonce,
+                hash,
+                signature: String::new(),
+            },
+            transactions: transactions.to_vec(),
         }
     }
 
-    pub fn new(index: usize, previous_hash: &str, timestamp: u64, data: &str) -> Block {
-        let hash = calculate_hash(index, previous_hash, timestamp, data);
-        Block {
+    pub fn new(
+        algorithm: HashAlgorithm,
+        index: usize,
+        previous_hash: BlockHash,
+        timestamp: u64,
+        transactions: &[Transaction],
+        difficulty: u32,
+        nonce: u64,
+    ) -> Block {
+        let mut block = Block::_new_with_hash(
             index,
-            previous_hash: previous_hash.to_string(),
+            previous_hash,
             timestamp,
-            data: data.to_string(),
-            hash,
+            transactions,
+            difficulty,
+            nonce,
+            BlockHash::default(),
+        );
+        block.header.hash = block.header.calculate_hash(algorithm);
+        block
+    }
+
+    /// Reassembles a block from a header and the body sent for it, checking
+    /// that the body is the one the header commits to.
+    pub fn from_parts(header: BlockHeader, body: BlockBody) -> Result<Block, String> {
+        if body.hash != header.hash {
+            return Err(format!(
+                "body of block {} sent for block {}",
+                body.hash, header.hash
+            ));
+        }
+        if calculate_merkle_root(&body.transactions) != header.merkle_root {
+            return Err(format!(
+                "body of block {} does not match its merkle root",
+                header.hash
+            ));
+        }
+        Ok(Block {
+            header,
+            transactions: body.transactions,
+        })
+    }
+
+    pub fn into_body(self) -> BlockBody {
+        BlockBody {
+            hash: self.header.hash,
+            transactions: self.transactions,
+        }
+    }
+}
+
+/// Transactions of block `hash`, sent apart from its header during
+/// headers-first sync.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BlockBody {
+    pub hash: BlockHash,
+    pub transactions: Vec<Transaction>,
+}
+
+/// Parameters of the chain: which consensus engine to run and how it is
+/// configured, along with the initial ledger state.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChainParams {
+    /// Leading zero bits required for the first block after genesis.
+    pub initial_difficulty: u32,
+    /// Target number of seconds between two blocks.
+    pub block_interval: u64,
+    /// Number of blocks between two difficulty adjustments.
+    pub adjustment_interval: usize,
+    /// Balances credited to accounts before the first block.
+    pub allocations: BTreeMap<String, u64>,
+    pub ledger: LedgerMode,
+    /// Coins created by each block and paid to its miner.
+    pub block_subsidy: u64,
+    /// Number of blocks after which the subsidy is halved, if ever.
+    pub halving_interval: Option<usize>,
+    /// Consensus engine built by [`crate::consensus::from_params`].
+    pub consensus: ConsensusMode,
+    /// Hex encoded public keys of the proof-of-authority validators, in the
+    /// order they take turns.
+    pub validators: Vec<String>,
+    /// Number of most recent blocks whose median timestamp a new block has
+    /// to exceed. Zero disables the rule.
+    pub median_time_span: usize,
+    /// Height up to which blocks hashed with the legacy header encoding are
+    /// still accepted, for chains started before header versions existed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub legacy_header_height: Option<usize>,
+    /// Hash function used for block hashes.
+    pub hash_algorithm: HashAlgorithm,
+    /// Function whose output has to meet the difficulty under proof-of-work.
+    pub pow_function: PowFunction,
+    /// Memory used by each evaluation of a memory-hard `pow_function`, in KiB.
+    pub pow_memory_kib: u32,
+    /// Passes over that memory.
+    pub pow_iterations: u32,
+}
+
+impl ChainParams {
+    /// Rejects parameters no chain can be run with.
+    pub fn check(&self) -> Result<(), String> {
+        if self.adjustment_interval == 0 {
+            return Err("adjustment interval must be at least 1".to_string());
         }
+        self.pow().check()
     }
+
+    pub fn pow(&self) -> PowParams {
+        PowParams {
+            function: self.pow_function,
+            memory_kib: self.pow_memory_kib,
+            iterations: self.pow_iterations,
+        }
+    }
+
+    /// Whether a block at `index` may hash its header with encoding `version`.
+    pub fn accepts_header_version(&self, index: usize, version: u32) -> bool {
+        match version {
+            BLOCK_VERSION => true,
+            LEGACY_BLOCK_VERSION => self
+                .legacy_header_height
+                .is_some_and(|height| index <= height),
+            _ => false,
+        }
+    }
+
+    pub fn block_subsidy(&self, index: usize) -> u64 {
+        match self.halving_interval {
+            Some(interval) if interval > 0 => {
+                let halvings = (index / interval).min(u64::BITS as usize) as u32;
+                self.block_subsidy.checked_shr(halvings).unwrap_or(0)
+            }
+            _ => self.block_subsidy,
+        }
+    }
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        ChainParams {
+            initial_difficulty: 16,
+            block_interval: 10,
+            adjustment_interval: 10,
+            allocations: BTreeMap::new(),
+            ledger: LedgerMode::Account,
+            block_subsidy: 50,
+            halving_interval: None,
+            consensus: ConsensusMode::ProofOfWork,
+            validators: vec![],
+            median_time_span: 11,
+            legacy_header_height: None,
+            hash_algorithm: HashAlgorithm::default(),
+            pow_function: PowFunction::default(),
+            pow_memory_kib: 4 * 1024,
+            pow_iterations: 1,
+        }
+    }
+}
+
+/// Blocks removed from and added to the canonical chain when its tip moves.
+///
+/// `disconnected` is empty when the tip was simply extended, otherwise it
+/// lists the blocks of the abandoned branch from the fork point upwards so
+/// that consumers can roll them back before applying `connected`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Reorg {
+    pub disconnected: Vec<Block>,
+    pub connected: Vec<Block>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Outcome of offering a block to the block tree.
+#[derive(Debug)]
+pub enum BlockStatus {
+    /// The block is already part of the tree or waiting as an orphan.
+    Known,
+    /// The block failed validation for the given reason and was dropped.
+    Invalid(String),
+    /// The parent of the block is unknown; it is kept until the parent arrives.
+    Orphan,
+    /// The block was stored on a branch that is not heavier than our tip.
+    SideChain,
+    /// The canonical chain changed.
+    NewTip(Reorg),
+    /// The block store could not be put back on our tip after a failed
+    /// write. The node has to stop and recover from its write-ahead log at
+    /// the next start.
+    Fatal(String),
+}
+
+/// Maximum number of blocks kept while waiting for their parent.
+const MAX_ORPHAN_BLOCKS: usize = 100;
+
+/// Side chain blocks further than this below our tip are forgotten, along
+/// with their descendants.
+const MAX_SIDE_CHAIN_DEPTH: usize = 100;
+
+/// Default number of seconds a block timestamp may be ahead of our clock.
+pub const DEFAULT_MAX_FUTURE_DRIFT: u64 = 2 * 60 * 60;
+
+#[derive(Debug)]
 pub struct Chain {
-    pub next_index: usize,
-    pub chains: Vec<Block>,
+    pub params: ChainParams,
+    /// Validity rules for new blocks and the fork choice rule.
+    consensus: Box<dyn Consensus>,
+    /// Number of seconds a block timestamp may be ahead of our clock. Unlike
+    /// `params` it is local policy that nodes of a network may disagree on.
+    max_future_drift: u64,
+    /// The canonical chain, from genesis to the heaviest known tip.
+    store: Box<dyn BlockStore>,
+    /// Valid blocks that are not part of the canonical chain, keyed by hash.
+    blocks: HashMap<BlockHash, Block>,
+    /// Cumulative weight of the branch ending at each known block, as
+    /// defined by [`Consensus::block_weight`].
+    work: HashMap<BlockHash, u128>,
+    /// Heaviest known block, which the canonical tip moves to.
+    best_block: BlockHash,
+    /// Blocks whose parent we have not seen yet, keyed by hash.
+    orphans: HashMap<BlockHash, Block>,
+    /// Ledger state at the canonical tip.
+    state: WorldState,
+    /// Undo data of every canonical block after genesis, in chain order.
+    undo: Vec<BlockUndo>,
 }
 
 impl Chain {
-    pub fn new(genesis_block: Block) -> Self {
-        let chains: Vec<Block> = vec![genesis_block];
-        let next_index: usize = 1;
-        Chain { next_index, chains }
+    /// Builds the chain on top of `store`, validating every stored block
+    /// again. Anything after the first invalid block is discarded. Fails if
+    /// `store` holds a chain starting with another genesis block.
+    pub fn new(
+        genesis_block: Block,
+        params: ChainParams,
+        consensus: Box<dyn Consensus>,
+        mut store: Box<dyn BlockStore>,
+        max_future_drift: u64,
+    ) -> io::Result<Self> {
+        match store.get_by_index(0) {
+            None => store.append(genesis_block.clone())?,
+            Some(stored) if stored != genesis_block => {
+                return Err(io::Error::other(format!(
+                    "stored chain starts with genesis block {}, ours is {}: \
+                     use the chain parameters it was created with or another data directory",
+                    stored.header.hash, genesis_block.header.hash
+                )));
+            }
+            Some(_) => {}
+        }
+        let state = WorldState::new(&params, &genesis_block.header.hash.to_string());
+        let mut chain = Chain {
+            params,
+            consensus,
+            max_future_drift,
+            store,
+            blocks: HashMap::new(),
+            work: HashMap::new(),
+            best_block: genesis_block.header.hash,
+            orphans: HashMap::new(),
+            state,
+            undo: vec![],
+        };
+        chain.work.insert(
+            genesis_block.header.hash,
+            chain.consensus.block_weight(&genesis_block.header),
+        );
+
+        // Stored blocks were validated before we stored them, so seals of a
+        // memory-hard proof-of-work, which are expensive to verify, are not
+        // verified again.
+        let skip_seals = chain.params.consensus == ConsensusMode::ProofOfWork
+            && chain.params.pow_function == PowFunction::Argon2id;
+        let window = chain.ancestor_window();
+        let mut ancestors = vec![genesis_block.header];
+        let mut height = 1;
+        while let Some(block) = chain.store.get_by_index(height) {
+            let checked = chain.check_body(&block).and_then(|()| {
+                if skip_seals {
+                    chain.check_unsealed_header(&ancestors, &block.header)
+                } else {
+                    chain.check_next_header(&ancestors, &block.header)
+                }
+            });
+            if let Err(e) = checked {
+                log::warn!("stored block {} is invalid: {e}", block.header.index);
+                break;
+            }
+            let subsidy = chain.params.block_subsidy(block.header.index);
+            match chain.state.apply_block(&block, subsidy) {
+                Ok(undo) => chain.undo.push(undo),
+                Err(e) => {
+                    log::warn!(
+                        "stored block {} is rejected by the ledger: {e}",
+                        block.header.index
+                    );
+                    break;
+                }
+            }
+            let header = block.header;
+            let work = chain.work[&header.previous_hash] + chain.consensus.block_weight(&header);
+            chain.work.insert(header.hash, work);
+            chain.best_block = header.hash;
+            ancestors.push(header);
+            if ancestors.len() > window {
+                ancestors.remove(0);
+            }
+            height += 1;
+        }
+        if height < chain.store.height() {
+            log::warn!(
+                "stored chain is invalid after height {}, discarding {} blocks",
+                height,
+                chain.store.height() - height
+            );
+            chain.store.truncate(height)?;
+        }
+        log::info!("loaded {} blocks from storage", height);
+        Ok(chain)
     }
 
-    pub fn replace_block_chain(&mut self, new_blocks: Vec<Block>) -> bool {
-        if Self::is_valid_chain(&new_blocks) && self.chains.len() < new_blocks.len() {
-            log::info!("Received blockchain is valid. Replacing current blockchain with received blockchain");
-            self.chains = new_blocks;
-            return true;
-        } else {
-            log::error!("Received blockchain is invalid");
+    /// Adds every block of a complete chain received from a peer to the tree
+    /// and switches to it if it carries more work than our current tip.
+    pub fn replace_block_chain(&mut self, new_blocks: Vec<Block>) -> BlockStatus {
+        if let Err(e) = self.check_chain(&new_blocks) {
+            log::error!("Received blockchain is invalid: {e}");
+            return BlockStatus::Invalid(e);
         }
-        false
+        for block in new_blocks {
+            self.orphans.remove(&block.header.hash);
+            if !self.work.contains_key(&block.header.hash) {
+                self.store_block(block);
+            }
+        }
+        self.update_tip()
     }
-    pub fn get_genesis_block() -> Block {
-        Block::new(0, "0", 1723020013, "genesis")
+    /// Difficulty the next block on top of our chain has to declare.
+    pub fn get_difficulty(&self) -> u32 {
+        let latest_block = self.get_latest_block().unwrap();
+        self.consensus
+            .expected_difficulty(&self.recent_headers(&latest_block.header.hash))
+    }
+
+    pub fn consensus(&self) -> &dyn Consensus {
+        self.consensus.as_ref()
     }
-    pub fn is_valid_chain(chain: &Vec<Block>) -> bool {
-        if chain.get(0).unwrap() != &Self::get_genesis_block() {
-            return false;
+
+    /// Checks a complete chain received from a peer, returning why it is
+    /// invalid if it is.
+    pub fn check_chain(&self, chain: &[Block]) -> Result<(), String> {
+        if chain.first() != self.store.get_by_index(0).as_ref() {
+            return Err("chain does not start with our genesis block".to_string());
         }
-        for (b1, b2) in chain.iter().zip(chain.iter().skip(1)) {
-            if !Self::is_valid_new_block(b2, b1) {
-                return false;
+        let headers: Vec<BlockHeader> = chain.iter().map(|block| block.header.clone()).collect();
+        for i in 1..chain.len() {
+            if self.work.contains_key(&headers[i].hash) {
+                // Already validated, and its hash commits to its header: only
+                // the blocks we do not know yet are worth verifying.
+                continue;
             }
+            self.check_next_block(&headers[..i], &chain[i])
+                .map_err(|e| format!("block {}: {e}", headers[i].index))?;
+        }
+        Ok(())
+    }
+
+    /// Checks a branch of headers received ahead of their bodies, whose
+    /// first header extends a block we know. The first `validated` headers
+    /// were checked already. Returns the total work of the branch.
+    pub fn check_headers(&self, headers: &[BlockHeader], validated: usize) -> Result<u128, String> {
+        let Some(first) = headers.first() else {
+            return Err("no headers".to_string());
+        };
+        let Some(mut work) = self.work.get(&first.previous_hash).copied() else {
+            return Err(format!("header {} extends an unknown block", first.index));
+        };
+        let window = self.ancestor_window();
+        let mut ancestors = self.recent_headers(&first.previous_hash);
+        for (i, header) in headers.iter().enumerate() {
+            if i >= validated {
+                self.check_next_header(&ancestors, header)
+                    .map_err(|e| format!("header {}: {e}", header.index))?;
+            }
+            work = work.saturating_add(self.consensus.block_weight(header));
+            ancestors.push(header.clone());
+            if ancestors.len() > window {
+                ancestors.remove(0);
+            }
+        }
+        Ok(work)
+    }
+
+    /// Validates `new_block` on top of `ancestors`, the most recent headers
+    /// of the branch it extends (see [`Chain::recent_headers`]).
+    pub fn check_next_block(
+        &self,
+        ancestors: &[BlockHeader],
+        new_block: &Block,
+    ) -> Result<(), String> {
+        self.check_body(new_block)?;
+        self.check_next_header(ancestors, &new_block.header)
+    }
+
+    /// Checks the transactions of a block against its header.
+    fn check_body(&self, block: &Block) -> Result<(), String> {
+        if calculate_merkle_root(&block.transactions) != block.header.merkle_root {
+            Err("invalid merkle root".to_string())
+        } else if !block
+            .transactions
+            .iter()
+            .all(|transaction| transaction.is_coinbase() || transaction.verify_signature())
+        {
+            Err("invalid transaction signature".to_string())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Validates `header` on top of `ancestors` without looking at the
+    /// transactions of the block.
+    pub fn check_next_header(
+        &self,
+        ancestors: &[BlockHeader],
+        header: &BlockHeader,
+    ) -> Result<(), String> {
+        self.check_unsealed_header(ancestors, header)?;
+        // The seal comes last as it may be by far the most expensive check.
+        self.consensus
+            .is_valid_seal(header, ancestors.last().unwrap())
+    }
+
+    /// Every check of [`Chain::check_next_header`] but the seal, for headers
+    /// whose seal was verified before.
+    fn check_unsealed_header(
+        &self,
+        ancestors: &[BlockHeader],
+        header: &BlockHeader,
+    ) -> Result<(), String> {
+        self.check_new_header(header, ancestors.last().unwrap())?;
+        self.check_timestamp(ancestors, header)?;
+        let expected_difficulty = self.consensus.expected_difficulty(ancestors);
+        if header.difficulty != expected_difficulty {
+            return Err(format!(
+                "invalid difficulty {}, expected {}",
+                header.difficulty, expected_difficulty
+            ));
         }
-        true
+        Ok(())
     }
-    pub fn is_valid_new_block(new_block: &Block, previous_block: &Block) -> bool {
-        log::info!("new block {:?}, prev block {:?}", new_block, previous_block);
-        if previous_block.index + 1 != new_block.index {
-            println!(
+    pub fn check_new_header(
+        &self,
+        header: &BlockHeader,
+        previous_header: &BlockHeader,
+    ) -> Result<(), String> {
+        log::info!("new header {:?}, prev header {:?}", header, previous_header);
+        if previous_header.index + 1 != header.index {
+            Err(format!(
                 "invalid index {}, {}",
-                previous_block.index, new_block.index
+                previous_header.index, header.index
+            ))
+        } else if previous_header.hash != header.previous_hash {
+            Err("invalid previoushash".to_string())
+        } else if !self
+            .params
+            .accepts_header_version(header.index, header.version)
+        {
+            Err(format!(
+                "header version {} is not accepted at height {}",
+                header.version, header.index
+            ))
+        } else if header.calculate_hash(self.params.hash_algorithm) != header.hash {
+            Err("invalid hash".to_string())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// A block must be timestamped after the median of the last
+    /// `median_time_span` blocks, so that a single miner cannot drag the
+    /// chain time backwards, and no more than `max_future_drift` seconds
+    /// ahead of our clock.
+    fn check_timestamp(
+        &self,
+        ancestors: &[BlockHeader],
+        header: &BlockHeader,
+    ) -> Result<(), String> {
+        if let Some(median) = self.median_time_past(ancestors) {
+            if header.timestamp <= median {
+                return Err(format!(
+                    "timestamp {} is not after the median {} of the last {} blocks",
+                    header.timestamp,
+                    median,
+                    self.params.median_time_span.min(ancestors.len())
+                ));
+            }
+        }
+        let latest_allowed = get_timestamp() + self.max_future_drift;
+        if header.timestamp > latest_allowed {
+            return Err(format!(
+                "timestamp {} is more than {} seconds in the future",
+                header.timestamp, self.max_future_drift
+            ));
+        }
+        Ok(())
+    }
+
+    /// Median timestamp of the last `median_time_span` of `ancestors`, if
+    /// the rule is enabled.
+    fn median_time_past(&self, ancestors: &[BlockHeader]) -> Option<u64> {
+        let span = self.params.median_time_span.min(ancestors.len());
+        if span == 0 {
+            return None;
+        }
+        let mut timestamps: Vec<u64> = ancestors[ancestors.len() - span..]
+            .iter()
+            .map(|header| header.timestamp)
+            .collect();
+        timestamps.sort_unstable();
+        Some(timestamps[span / 2])
+    }
+
+    /// Earliest timestamp the next block on top of our tip may carry when
+    /// produced by the holder of `public_key`, if any.
+    pub fn earliest_timestamp(&self, public_key: Option<&str>) -> u64 {
+        let latest_block = self.get_latest_block().unwrap();
+        let ancestors = self.recent_headers(&latest_block.header.hash);
+        let after_median = self
+            .median_time_past(&ancestors)
+            .map_or(0, |median| median + 1);
+        after_median.max(
+            self.consensus
+                .earliest_timestamp(&latest_block.header, public_key),
+        )
+    }
+
+    /// Total weight of our chain, used as the fork choice rule.
+    pub fn cumulative_work(&self) -> u128 {
+        self.work[&self.get_latest_block().unwrap().header.hash]
+    }
+
+    /// Whether `hash` is a validated block of our block tree.
+    pub fn knows(&self, hash: &BlockHash) -> bool {
+        self.work.contains_key(hash)
+    }
+
+    /// Block `hash` of the canonical chain or of a side chain.
+    pub fn get_block(&self, hash: &BlockHash) -> Option<Block> {
+        self.blocks
+            .get(hash)
+            .cloned()
+            .or_else(|| self.store.get_by_hash(hash))
+    }
+
+    /// Headers of up to `count` canonical blocks, starting at index `start`.
+    pub fn headers(&self, start: usize, count: usize) -> Vec<BlockHeader> {
+        let end = start.saturating_add(count).min(self.store.height());
+        self.store
+            .blocks(start..end)
+            .into_iter()
+            .map(|block| block.header)
+            .collect()
+    }
+
+    pub fn get_latest_block(&self) -> Option<Block> {
+        self.store.tip()
+    }
+
+    /// Index the next block on top of our chain will have.
+    pub fn next_index(&self) -> usize {
+        self.store.height()
+    }
+
+    pub fn get_account(&self, address: &str) -> Account {
+        self.state.get_account(address)
+    }
+
+    pub fn unspent_outputs(&self, address: &str) -> Vec<UnspentOutput> {
+        self.state.unspent_outputs(address)
+    }
+
+    /// Ledger state at the canonical tip.
+    pub fn state(&self) -> &WorldState {
+        &self.state
+    }
+
+    /// Transactions of the next block: the `candidates`, ordered by nonce,
+    /// that can be applied in turn on top of our tip, up to `limit` of them.
+    /// When a `miner_address` is given they are preceded by a coinbase
+    /// paying it the block subsidy and their fees.
+    pub fn block_transactions(
+        &self,
+        mut candidates: Vec<Transaction>,
+        limit: usize,
+        miner_address: Option<&str>,
+    ) -> Vec<Transaction> {
+        candidates.sort_by_key(|transaction| transaction.nonce);
+        let mut state = self.state.clone();
+        let mut fees: u64 = 0;
+        let mut transactions: Vec<Transaction> = candidates
+            .into_iter()
+            .filter(|transaction| match state.apply_transaction(transaction) {
+                Ok((_, fee)) => {
+                    fees = fees.saturating_add(fee);
+                    true
+                }
+                Err(_) => false,
+            })
+            .take(limit)
+            .collect();
+        if let Some(miner_address) = miner_address {
+            let index = self.next_index();
+            let reward = self.params.block_subsidy(index).saturating_add(fees);
+            let coinbase = Transaction::coinbase(index, miner_address, reward, self.params.ledger);
+            transactions.insert(0, coinbase);
+        }
+        transactions
+    }
+
+    /// Proves that transaction `item_index` of canonical block `block_index`
+    /// is committed to by the block's Merkle root.
+    pub fn merkle_proof(&self, block_index: usize, item_index: usize) -> Option<MerkleProof> {
+        let block = self.store.get_by_index(block_index)?;
+        let ids: Vec<String> = block.transactions.iter().map(Transaction::id).collect();
+        merkle::merkle_proof(&ids, item_index)
+    }
+
+    /// Every block of the canonical chain, from genesis to tip.
+    pub fn get_blocks(&self) -> Vec<Block> {
+        self.store.blocks(0..self.store.height())
+    }
+
+    /// Offers a single block to the tree. The block may extend our tip, land
+    /// on a side branch (possibly making it the heaviest one) or wait as an
+    /// orphan until its parent is known.
+    pub fn add_block(&mut self, block: Block) -> BlockStatus {
+        match self.insert_block(block, false) {
+            BlockStatus::SideChain => self.update_tip(),
+            status => status,
+        }
+    }
+
+    /// Like [`Chain::add_block`] for a block whose header was validated by
+    /// [`Chain::check_headers`]: only its body is checked.
+    pub fn add_block_with_checked_header(&mut self, block: Block) -> BlockStatus {
+        match self.insert_block(block, true) {
+            BlockStatus::SideChain => self.update_tip(),
+            status => status,
+        }
+    }
+
+    fn insert_block(&mut self, block: Block, header_checked: bool) -> BlockStatus {
+        let hash = block.header.hash;
+        if self.work.contains_key(&hash) || self.orphans.contains_key(&hash) {
+            return BlockStatus::Known;
+        }
+        if !self.work.contains_key(&block.header.previous_hash) {
+            if self.orphans.len() >= MAX_ORPHAN_BLOCKS {
+                let evicted = self.orphans.keys().next().cloned().unwrap();
+                self.orphans.remove(&evicted);
+            }
+            log::info!("block {hash} is an orphan");
+            self.orphans.insert(hash, block);
+            return BlockStatus::Orphan;
+        }
+        let checked = if header_checked {
+            self.check_body(&block)
+        } else {
+            let ancestors = self.recent_headers(&block.header.previous_hash);
+            self.check_next_block(&ancestors, &block)
+        };
+        if let Err(e) = checked {
+            log::warn!("block {hash} is invalid: {e}");
+            return BlockStatus::Invalid(e);
+        }
+        self.store_block(block);
+
+        let children: Vec<BlockHash> = self
+            .orphans
+            .values()
+            .filter(|orphan| orphan.header.previous_hash == hash)
+            .map(|orphan| orphan.header.hash)
+            .collect();
+        for child in children {
+            if let Some(orphan) = self.orphans.remove(&child) {
+                self.insert_block(orphan, false);
+            }
+        }
+        BlockStatus::SideChain
+    }
+
+    /// Records an already validated block whose parent is known as a side
+    /// chain block.
+    fn store_block(&mut self, block: Block) {
+        let parent_work = self.work[&block.header.previous_hash];
+        let work = parent_work.saturating_add(self.consensus.block_weight(&block.header));
+        if work > self.work[&self.best_block] {
+            self.best_block = block.header.hash;
+        }
+        self.work.insert(block.header.hash, work);
+        self.blocks.insert(block.header.hash, block);
+    }
+
+    /// Number of most recent blocks needed to validate the next block.
+    fn ancestor_window(&self) -> usize {
+        self.consensus
+            .ancestor_window()
+            .max(self.params.median_time_span)
+    }
+
+    /// Returns the last headers of the branch ending at the known block
+    /// `hash`, enough of them to validate a block on top of it.
+    fn recent_headers(&self, hash: &BlockHash) -> Vec<BlockHeader> {
+        let window = self.ancestor_window();
+        let mut branch: Vec<BlockHeader> = vec![];
+        let mut current = *hash;
+        while branch.len() < window {
+            let Some(block) = self.blocks.get(&current) else {
+                let index = self.store.get_by_hash(&current).unwrap().header.index;
+                let start = (index + 1).saturating_sub(window - branch.len());
+                let mut recent = self.headers(start, index + 1 - start);
+                recent.extend(branch.into_iter().rev());
+                return recent;
+            };
+            current = block.header.previous_hash;
+            branch.push(block.header.clone());
+        }
+        branch.reverse();
+        branch
+    }
+
+    /// Moves the canonical chain to the heaviest known branch.
+    fn update_tip(&mut self) -> BlockStatus {
+        let mut current = self.best_block;
+        if self.get_latest_block().unwrap().header.hash == current {
+            return BlockStatus::SideChain;
+        }
+        let mut connected: Vec<Block> = vec![];
+        while let Some(block) = self.blocks.get(&current) {
+            current = block.header.previous_hash;
+            connected.push(block.clone());
+        }
+        connected.reverse();
+        let fork = connected[0].header.index;
+        let reorg = Reorg {
+            disconnected: self.store.blocks(fork..self.store.height()),
+            connected,
+        };
+
+        if let Err((block_hash, e)) = self.apply_reorg_to_state(&reorg) {
+            log::warn!("block {block_hash} is rejected by the ledger: {e}");
+            self.discard_branch(&block_hash);
+            self.best_block = self.heaviest_block();
+            return match self.update_tip() {
+                BlockStatus::SideChain => BlockStatus::Invalid(e),
+                status => status,
+            };
+        }
+        if !reorg.disconnected.is_empty() {
+            log::info!(
+                "Reorganizing at height {}: {} blocks disconnected, {} blocks connected",
+                fork,
+                reorg.disconnected.len(),
+                reorg.connected.len()
             );
-            return false;
-        } else if previous_block.hash != new_block.previous_hash {
-            println!("invalid previoushash");
-            return false;
-        } else if calculate_hash_from_block(new_block) != new_block.hash {
-            println!("invalid hash");
-            return false;
         }
-        true
+        if let Err(e) = self.store.apply(fork, &reorg.connected) {
+            // Keep the blocks as a side chain: the ledger and the store are
+            // put back on the old tip so that they still line up.
+            log::error!("failed to persist the chain: {e}");
+            self.revert_reorg_from_state(reorg.connected.len(), &reorg.disconnected);
+            if let Err(e) = self.store.apply(fork, &reorg.disconnected) {
+                return BlockStatus::Fatal(format!("failed to restore the stored chain: {e}"));
+            }
+            return BlockStatus::Invalid(format!("failed to persist the chain: {e}"));
+        }
+        for block in &reorg.connected {
+            self.blocks.remove(&block.header.hash);
+        }
+        for block in &reorg.disconnected {
+            self.blocks.insert(block.header.hash, block.clone());
+        }
+        self.prune_side_chains();
+        BlockStatus::NewTip(reorg)
     }
 
-    pub fn get_latest_block(&self) -> Option<&Block> {
-        self.chains.last()
+    /// Heaviest of our tip and the side chain blocks.
+    fn heaviest_block(&self) -> BlockHash {
+        let tip = self.get_latest_block().unwrap().header.hash;
+        self.blocks.keys().copied().fold(tip, |best, hash| {
+            if self.work[&hash] > self.work[&best] {
+                hash
+            } else {
+                best
+            }
+        })
     }
 
-    pub fn add_block(&mut self, block: Block) {
-        if Chain::is_valid_new_block(&block, self.get_latest_block().unwrap()) {
-            self.chains.push(block);
-            self.next_index += 1;
+    /// Forgets the side chain blocks more than [`MAX_SIDE_CHAIN_DEPTH`]
+    /// blocks below our tip.
+    fn prune_side_chains(&mut self) {
+        let Some(min_index) = self.next_index().checked_sub(MAX_SIDE_CHAIN_DEPTH + 1) else {
+            return;
+        };
+        let deep: Vec<BlockHash> = self
+            .blocks
+            .values()
+            .filter(|block| block.header.index < min_index)
+            .map(|block| block.header.hash)
+            .collect();
+        for hash in deep {
+            if self.blocks.contains_key(&hash) {
+                self.discard_branch(&hash);
+            }
+        }
+    }
+
+    /// Rolls the ledger back over `reorg.disconnected` and forward over
+    /// `reorg.connected`. On failure the ledger is restored and the hash of
+    /// the offending block is returned.
+    fn apply_reorg_to_state(&mut self, reorg: &Reorg) -> Result<(), (BlockHash, String)> {
+        for _ in &reorg.disconnected {
+            let undo = self.undo.pop().unwrap();
+            self.state.revert_block(undo);
+        }
+        for (applied, block) in reorg.connected.iter().enumerate() {
+            match self
+                .state
+                .apply_block(block, self.params.block_subsidy(block.header.index))
+            {
+                Ok(undo) => self.undo.push(undo),
+                Err(e) => {
+                    self.revert_reorg_from_state(applied, &reorg.disconnected);
+                    return Err((block.header.hash, e));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Undoes the last `applied` blocks of the ledger and applies the
+    /// `disconnected` blocks again, putting it back on the previous tip.
+    fn revert_reorg_from_state(&mut self, applied: usize, disconnected: &[Block]) {
+        for _ in 0..applied {
+            let undo = self.undo.pop().unwrap();
+            self.state.revert_block(undo);
+        }
+        for block in disconnected {
+            let subsidy = self.params.block_subsidy(block.header.index);
+            let undo = self.state.apply_block(block, subsidy).unwrap();
+            self.undo.push(undo);
+        }
+    }
+
+    /// Forgets the side chain block `hash` and every known descendant of it.
+    fn discard_branch(&mut self, hash: &BlockHash) {
+        let mut discarded = vec![*hash];
+        while let Some(hash) = discarded.pop() {
+            self.blocks.remove(&hash);
+            self.work.remove(&hash);
+            discarded.extend(
+                self.blocks
+                    .values()
+                    .filter(|block| block.header.previous_hash == hash)
+                    .map(|block| block.header.hash),
+            );
         }
     }
 }
-pub fn calculate_hash(index: usize, previous_hash: &str, timestamp: u64, data: &str) -> String {
-    let block_data = format!("{}{}{}{}", index, previous_hash, timestamp, data);
-    let mut hasher = Sha256::new();
-    hasher.update(block_data);
-    let result = hasher.finalize();
-    format!("{:x}", result)
+pub fn calculate_merkle_root(transactions: &[Transaction]) -> String {
+    let ids: Vec<String> = transactions.iter().map(Transaction::id).collect();
+    merkle::merkle_root(&ids)
 }
-pub fn calculate_hash_from_block(block: &Block) -> String {
-    let block_data = format!(
-        "{}{}{}{}",
-        block.index, block.previous_hash, block.timestamp, block.data
-    );
-    let mut hasher = Sha256::new();
-    hasher.update(block_data);
-    let result = hasher.finalize();
-    format!("{:x}", result)
+/// Bytes hashed into the hash of a block header of the given `version`.
+pub fn header_preimage(
+    version: u32,
+    index: usize,
+    previous_hash: &BlockHash,
+    timestamp: u64,
+    merkle_root: &str,
+    difficulty: u32,
+    nonce: u64,
+) -> Vec<u8> {
+    if version == LEGACY_BLOCK_VERSION {
+        format!(
+            "{}{}{}{}{}{}",
+            index, previous_hash, timestamp, merkle_root, difficulty, nonce
+        )
+        .into_bytes()
+    } else {
+        encode_header(
+            version,
+            index,
+            previous_hash,
+            timestamp,
+            merkle_root,
+            difficulty,
+            nonce,
+        )
+    }
+}
+/// Canonical encoding of a block header hashed by version 1 blocks: the
+/// version followed by every field in order, integers as fixed width big
+/// endian, hashes as their 32 raw bytes and strings prefixed with their
+/// length in bytes, so that distinct headers never share an encoding.
+pub fn encode_header(
+    version: u32,
+    index: usize,
+    previous_hash: &BlockHash,
+    timestamp: u64,
+    merkle_root: &str,
+    difficulty: u32,
+    nonce: u64,
+) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(4 + 8 + 32 + 8 + 4 + merkle_root.len() + 4 + 8);
+    encoded.extend_from_slice(&version.to_be_bytes());
+    encoded.extend_from_slice(&(index as u64).to_be_bytes());
+    encoded.extend_from_slice(previous_hash.as_bytes());
+    encoded.extend_from_slice(&timestamp.to_be_bytes());
+    encode_bytes(&mut encoded, merkle_root.as_bytes());
+    encoded.extend_from_slice(&difficulty.to_be_bytes());
+    encoded.extend_from_slice(&nonce.to_be_bytes());
+    encoded
+}
+
+fn encode_bytes(encoded: &mut Vec<u8>, bytes: &[u8]) {
+    encoded.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    encoded.extend_from_slice(bytes);
+}
+
+/// Expected number of hashes needed to mine a block of the given difficulty.
+pub fn calculate_block_work(difficulty: u32) -> u128 {
+    1u128.checked_shl(difficulty).unwrap_or(u128::MAX)
+}
+
+/// Checks that `hash` starts with at least `difficulty` zero bits.
+pub fn hash_matches_difficulty(hash: &BlockHash, difficulty: u32) -> bool {
+    hash.leading_zero_bits() >= difficulty
 }
 
 pub fn get_timestamp() -> u64 {
@@ -131,3 +1041,255 @@ pub fn get_timestamp() -> u64 {
         .unwrap();
     epoch.as_secs()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, coinbase_block};
+    use crate::transaction::generate_keypair;
+
+    fn balance(chain: &Chain, address: &str) -> u64 {
+        match chain.params.ledger {
+            LedgerMode::Account => chain.get_account(address).balance,
+            LedgerMode::Utxo => chain
+                .unspent_outputs(address)
+                .iter()
+                .map(|unspent| unspent.output.amount)
+                .sum(),
+        }
+    }
+
+    fn overtaking_branch_rolls_back_ledger(ledger: LedgerMode) {
+        let mut chain = testing::chain(ledger);
+        let genesis = chain.get_latest_block().unwrap();
+        let (alice, bob) = ("aa".repeat(32), "bb".repeat(32));
+
+        let main_1 = coinbase_block(&chain, &genesis, &alice, 1);
+        let main_2 = coinbase_block(&chain, &main_1, &alice, 1);
+        for block in [main_1, main_2.clone()] {
+            assert!(matches!(chain.add_block(block), BlockStatus::NewTip(_)));
+        }
+        assert_eq!(balance(&chain, &alice), 100);
+
+        let side_1 = coinbase_block(&chain, &genesis, &bob, 2);
+        let side_2 = coinbase_block(&chain, &side_1, &bob, 2);
+        let side_3 = coinbase_block(&chain, &side_2, &bob, 2);
+        for block in [side_1, side_2] {
+            assert!(matches!(chain.add_block(block), BlockStatus::SideChain));
+        }
+        assert_eq!(balance(&chain, &bob), 0);
+
+        let BlockStatus::NewTip(reorg) = chain.add_block(side_3.clone()) else {
+            panic!("the longer branch should become canonical");
+        };
+        assert_eq!(reorg.disconnected.len(), 2);
+        assert_eq!(reorg.connected.len(), 3);
+        assert_eq!(chain.get_latest_block().unwrap(), side_3);
+        assert_eq!(balance(&chain, &alice), 0);
+        assert_eq!(balance(&chain, &bob), 150);
+        assert!(chain.knows(&main_2.header.hash));
+    }
+
+    #[test]
+    fn overtaking_branch_rolls_back_accounts() {
+        overtaking_branch_rolls_back_ledger(LedgerMode::Account);
+    }
+
+    #[test]
+    fn overtaking_branch_rolls_back_unspent_outputs() {
+        overtaking_branch_rolls_back_ledger(LedgerMode::Utxo);
+    }
+
+    #[test]
+    fn rejects_block_with_tampered_transaction() {
+        let mut chain = testing::chain(LedgerMode::Account);
+        let genesis = chain.get_latest_block().unwrap();
+        let (alice, secret_key) = generate_keypair();
+        let funding = coinbase_block(&chain, &genesis, &alice, 1);
+        chain.add_block(funding.clone());
+
+        let mut transfer = Transaction {
+            sender: String::new(),
+            payload: String::new(),
+            recipient: Some("bb".repeat(32)),
+            amount: 10,
+            fee: 0,
+            inputs: vec![],
+            outputs: vec![],
+            nonce: 0,
+            signature: String::new(),
+        }
+        .sign(&secret_key)
+        .unwrap();
+        transfer.amount = 50;
+        let block = testing::block(&funding, &[transfer], 1);
+        let BlockStatus::Invalid(e) = chain.add_block(block) else {
+            panic!("a tampered transaction should be rejected");
+        };
+        assert_eq!(e, "invalid transaction signature");
+        assert_eq!(chain.get_latest_block().unwrap(), funding);
+        assert_eq!(chain.get_account(&alice).balance, 50);
+    }
+
+    #[test]
+    fn forgets_deep_side_branches() {
+        let mut chain = testing::chain(LedgerMode::Account);
+        let genesis = chain.get_latest_block().unwrap();
+        let side = testing::branch(&genesis, 2, 2);
+        let main = testing::branch(&genesis, MAX_SIDE_CHAIN_DEPTH + 2, 1);
+        for block in main.iter().take(3).chain(&side) {
+            chain.add_block(block.clone());
+        }
+        assert!(chain.knows(&side[1].header.hash));
+
+        for block in &main[3..] {
+            chain.add_block(block.clone());
+        }
+        assert!(!chain.knows(&side[0].header.hash));
+        assert!(!chain.knows(&side[1].header.hash));
+        assert!(chain.knows(&main[0].header.hash));
+    }
+
+    fn block_at(parent: &Block, timestamp: u64) -> Block {
+        let mut block = testing::block(parent, &[], 0);
+        block.header.timestamp = timestamp;
+        block.header.hash = block.header.calculate_hash(HashAlgorithm::Sha256);
+        block
+    }
+
+    #[test]
+    fn rejects_timestamp_not_after_median_time_past() {
+        let mut chain = testing::chain(LedgerMode::Account);
+        let genesis = chain.get_latest_block().unwrap();
+        let blocks = testing::branch(&genesis, 5, 0);
+        for block in &blocks {
+            chain.add_block(block.clone());
+        }
+        let tip = &blocks[4];
+        let median = genesis.header.timestamp + 3;
+
+        let BlockStatus::Invalid(e) = chain.add_block(block_at(tip, median)) else {
+            panic!("a block at the median time past should be rejected");
+        };
+        assert_eq!(
+            e,
+            format!("timestamp {median} is not after the median {median} of the last 6 blocks")
+        );
+        assert_eq!(chain.earliest_timestamp(None), median + 1);
+        assert!(matches!(
+            chain.add_block(block_at(tip, median + 1)),
+            BlockStatus::NewTip(_)
+        ));
+    }
+
+    #[test]
+    fn accepts_earlier_timestamp_without_median_time_past() {
+        let mut chain = testing::chain_with(ChainParams {
+            consensus: ConsensusMode::Naive,
+            median_time_span: 0,
+            ..ChainParams::default()
+        });
+        let genesis = chain.get_latest_block().unwrap();
+        let parent = testing::block(&genesis, &[], 0);
+        chain.add_block(parent.clone());
+        let earlier = block_at(&parent, genesis.header.timestamp - 1);
+        assert!(matches!(chain.add_block(earlier), BlockStatus::NewTip(_)));
+    }
+
+    #[test]
+    fn rejects_timestamp_too_far_in_the_future() {
+        let mut chain = testing::chain(LedgerMode::Account);
+        let genesis = chain.get_latest_block().unwrap();
+        let latest_allowed = get_timestamp() + DEFAULT_MAX_FUTURE_DRIFT;
+
+        let BlockStatus::Invalid(e) = chain.add_block(block_at(&genesis, latest_allowed + 60))
+        else {
+            panic!("a block too far in the future should be rejected");
+        };
+        assert_eq!(
+            e,
+            format!(
+                "timestamp {} is more than {DEFAULT_MAX_FUTURE_DRIFT} seconds in the future",
+                latest_allowed + 60
+            )
+        );
+        let ahead = block_at(&genesis, latest_allowed - 60);
+        assert!(matches!(chain.add_block(ahead), BlockStatus::NewTip(_)));
+    }
+
+    fn headers(blocks: &[Block]) -> Vec<BlockHeader> {
+        blocks.iter().map(|block| block.header.clone()).collect()
+    }
+
+    #[test]
+    fn checks_header_chain_ahead_of_bodies() {
+        let chain = testing::chain(LedgerMode::Account);
+        let genesis = chain.get_latest_block().unwrap();
+        let headers = headers(&testing::branch(&genesis, 3, 0));
+        assert_eq!(chain.check_headers(&headers, 0), Ok(4));
+        assert_eq!(
+            chain.check_headers(&headers[1..], 0),
+            Err("header 2 extends an unknown block".to_string())
+        );
+        assert_eq!(chain.check_headers(&[], 0), Err("no headers".to_string()));
+
+        let mut broken = headers.clone();
+        broken[2].previous_hash = genesis.header.hash;
+        broken[2].hash = broken[2].calculate_hash(HashAlgorithm::Sha256);
+        assert_eq!(
+            chain.check_headers(&broken, 0),
+            Err("header 3: invalid previoushash".to_string())
+        );
+        let mut forged = headers.clone();
+        forged[1].nonce += 1;
+        assert_eq!(
+            chain.check_headers(&forged, 0),
+            Err("header 2: invalid hash".to_string())
+        );
+        // Headers validated before are not checked again.
+        assert_eq!(chain.check_headers(&forged, 2), Ok(4));
+    }
+
+    #[test]
+    fn reassembles_block_from_matching_body_only() {
+        let chain = testing::chain(LedgerMode::Account);
+        let genesis = chain.get_latest_block().unwrap();
+        let block = coinbase_block(&chain, &genesis, &"aa".repeat(32), 0);
+        let other = coinbase_block(&chain, &genesis, &"bb".repeat(32), 0);
+
+        let body = block.clone().into_body();
+        assert_eq!(
+            Block::from_parts(block.header.clone(), body),
+            Ok(block.clone())
+        );
+        assert_eq!(
+            Block::from_parts(block.header.clone(), other.clone().into_body()),
+            Err(format!(
+                "body of block {} sent for block {}",
+                other.header.hash, block.header.hash
+            ))
+        );
+        let mut body = block.clone().into_body();
+        body.transactions = other.transactions;
+        assert_eq!(
+            Block::from_parts(block.header.clone(), body),
+            Err(format!(
+                "body of block {} does not match its merkle root",
+                block.header.hash
+            ))
+        );
+    }
+
+    #[test]
+    fn header_encoding_separates_colliding_legacy_fields() {
+        let hash = BlockHash::default();
+        let legacy = |timestamp, merkle_root| {
+            header_preimage(LEGACY_BLOCK_VERSION, 5, &hash, timestamp, merkle_root, 0, 0)
+        };
+        let current = |timestamp, merkle_root| {
+            header_preimage(BLOCK_VERSION, 5, &hash, timestamp, merkle_root, 0, 0)
+        };
+        assert_eq!(legacy(1, "23"), legacy(12, "3"));
+        assert_ne!(current(1, "23"), current(12, "3"));
+    }
+}
@@ -0,0 +1,428 @@
+use std::fmt::Debug;
+
+use serde::{Deserialize, Serialize};
+
+use crate::chain::{calculate_block_work, BlockHeader, ChainParams};
+use crate::pow::PowParams;
+
+/// How blocks are sealed and who may produce them.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ConsensusMode {
+    /// Anyone may produce a block at any time; the longest chain wins.
+    #[serde(rename = "naive")]
+    #[value(name = "naive")]
+    Naive,
+    /// Anyone may produce a block by finding a nonce meeting the difficulty.
+    #[default]
+    #[serde(rename = "pow")]
+    #[value(name = "pow")]
+    ProofOfWork,
+    /// Validators take turns producing blocks, which they sign.
+    #[serde(rename = "poa")]
+    #[value(name = "poa")]
+    ProofOfAuthority,
+}
+
+/// What a block producer has to do to seal a block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SealKind {
+    None,
+    /// Search for a nonce meeting the declared difficulty.
+    ProofOfWork,
+    /// Sign the block hash with the validator key.
+    Signature,
+}
+
+/// Rules deciding which blocks are valid, who may produce them and which
+/// branch of the block tree is canonical.
+pub trait Consensus: Debug + Send {
+    fn seal_kind(&self) -> SealKind;
+    /// Number of most recent blocks needed to validate the next block.
+    fn ancestor_window(&self) -> usize {
+        1
+    }
+    /// Difficulty declared by the block following the last of `ancestors`,
+    /// which holds at least [`Consensus::ancestor_window`] blocks.
+    fn expected_difficulty(&self, _ancestors: &[BlockHeader]) -> u32 {
+        0
+    }
+    /// Checks how the block of `header` was sealed on top of `previous_header`.
+    fn is_valid_seal(
+        &self,
+        header: &BlockHeader,
+        previous_header: &BlockHeader,
+    ) -> Result<(), String>;
+    /// Whether the holder of `public_key`, if any, may produce a block on
+    /// top of `previous_header` at time `now`.
+    fn can_produce(
+        &self,
+        _previous_header: &BlockHeader,
+        _public_key: Option<&str>,
+        _now: u64,
+    ) -> bool {
+        true
+    }
+    /// Earliest timestamp a block on top of `previous_header` produced by the
+    /// holder of `public_key`, if any, may carry.
+    fn earliest_timestamp(&self, _previous_header: &BlockHeader, _public_key: Option<&str>) -> u64 {
+        0
+    }
+    /// Weight the block of `header` adds to its branch. The fork choice rule
+    /// follows the branch with the highest total weight.
+    fn block_weight(&self, _header: &BlockHeader) -> u128 {
+        1
+    }
+}
+
+/// Builds the consensus engine selected by `params.consensus`.
+pub fn from_params(params: &ChainParams) -> Box<dyn Consensus> {
+    match params.consensus {
+        ConsensusMode::Naive => Box::new(Naive),
+        ConsensusMode::ProofOfWork => Box::new(ProofOfWork {
+            initial_difficulty: params.initial_difficulty,
+            block_interval: params.block_interval,
+            adjustment_interval: params.adjustment_interval,
+            pow: params.pow(),
+        }),
+        ConsensusMode::ProofOfAuthority => Box::new(ProofOfAuthority {
+            validators: params.validators.clone(),
+            block_interval: params.block_interval,
+        }),
+    }
+}
+
+/// The original rule: blocks only need to link up, the longest chain wins.
+#[derive(Debug)]
+pub struct Naive;
+
+impl Consensus for Naive {
+    fn seal_kind(&self) -> SealKind {
+        SealKind::None
+    }
+
+    fn is_valid_seal(
+        &self,
+        _header: &BlockHeader,
+        _previous_header: &BlockHeader,
+    ) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Nakamoto consensus: the hash of each block, or a memory-hard function of
+/// it, must start with a number of zero bits retargeted over time, and the
+/// branch with the most work wins.
+#[derive(Debug)]
+pub struct ProofOfWork {
+    /// Leading zero bits required for the first block after genesis.
+    pub initial_difficulty: u32,
+    /// Target number of seconds between two blocks.
+    pub block_interval: u64,
+    /// Number of blocks between two difficulty adjustments.
+    pub adjustment_interval: usize,
+    pub pow: PowParams,
+}
+
+impl Consensus for ProofOfWork {
+    fn seal_kind(&self) -> SealKind {
+        SealKind::ProofOfWork
+    }
+
+    fn ancestor_window(&self) -> usize {
+        self.adjustment_interval + 1
+    }
+
+    /// Every `adjustment_interval` blocks the time taken to mine the last
+    /// interval, measured from the block `adjustment_interval` steps back, is
+    /// compared to the target and the difficulty is raised or lowered by one
+    /// bit when blocks came in more than twice too fast or slow.
+    fn expected_difficulty(&self, ancestors: &[BlockHeader]) -> u32 {
+        let latest_block = ancestors.last().unwrap();
+        if latest_block.index == 0 {
+            return self.initial_difficulty;
+        }
+        let interval = self.adjustment_interval;
+        if interval == 0 || !(latest_block.index + 1).is_multiple_of(interval) {
+            return latest_block.difficulty;
+        }
+        let mut first = ancestors.len().saturating_sub(interval + 1);
+        // The genesis timestamp is set by the genesis file, long before block
+        // 1 was mined, so the first interval is measured from block 1.
+        if ancestors[first].index == 0 {
+            first += 1;
+        }
+        let adjustment_block = &ancestors[first];
+        let time_expected =
+            self.block_interval * (latest_block.index - adjustment_block.index) as u64;
+        let time_taken = latest_block
+            .timestamp
+            .saturating_sub(adjustment_block.timestamp);
+        if time_taken < time_expected / 2 {
+            latest_block.difficulty + 1
+        } else if time_taken > time_expected * 2 {
+            latest_block.difficulty.saturating_sub(1)
+        } else {
+            latest_block.difficulty
+        }
+    }
+
+    fn is_valid_seal(
+        &self,
+        header: &BlockHeader,
+        _previous_header: &BlockHeader,
+    ) -> Result<(), String> {
+        if !self.pow.meets(&header.hash, header.difficulty) {
+            return Err("invalid proof of work".to_string());
+        }
+        Ok(())
+    }
+
+    fn block_weight(&self, header: &BlockHeader) -> u128 {
+        calculate_block_work(header.difficulty)
+    }
+}
+
+/// Validators from a fixed list take turns in round-robin order, signing the
+/// blocks they produce at least `block_interval` seconds apart. When the
+/// validator whose turn it is stays silent for another `block_interval`, any
+/// other validator may sign the block instead. Such out-of-turn blocks weigh
+/// less, so that a competing in-turn block wins.
+#[derive(Debug)]
+pub struct ProofOfAuthority {
+    /// Hex encoded public keys of the validators, in the order they take turns.
+    pub validators: Vec<String>,
+    pub block_interval: u64,
+}
+
+impl ProofOfAuthority {
+    /// Validator expected to sign the block at `index`.
+    fn proposer(&self, index: usize) -> Option<&str> {
+        if self.validators.is_empty() {
+            return None;
+        }
+        Some(&self.validators[index % self.validators.len()])
+    }
+
+    /// Validator who signed `header`, trying the one whose turn it is first.
+    fn signer(&self, header: &BlockHeader) -> Option<&str> {
+        let proposer = self.proposer(header.index)?;
+        if header.verify_signature(proposer) {
+            return Some(proposer);
+        }
+        self.validators
+            .iter()
+            .map(String::as_str)
+            .find(|validator| header.verify_signature(validator))
+    }
+}
+
+impl Consensus for ProofOfAuthority {
+    fn seal_kind(&self) -> SealKind {
+        SealKind::Signature
+    }
+
+    fn is_valid_seal(
+        &self,
+        header: &BlockHeader,
+        previous_header: &BlockHeader,
+    ) -> Result<(), String> {
+        let signer = self
+            .signer(header)
+            .ok_or_else(|| format!("block {} is not signed by a validator", header.index))?;
+        if header.timestamp < self.earliest_timestamp(previous_header, Some(signer)) {
+            return Err(format!("block {} was proposed too early", header.index));
+        }
+        Ok(())
+    }
+
+    fn can_produce(
+        &self,
+        previous_header: &BlockHeader,
+        public_key: Option<&str>,
+        now: u64,
+    ) -> bool {
+        public_key.is_some_and(|key| self.validators.iter().any(|validator| validator == key))
+            && now >= self.earliest_timestamp(previous_header, public_key)
+    }
+
+    fn earliest_timestamp(&self, previous_header: &BlockHeader, public_key: Option<&str>) -> u64 {
+        let slot = previous_header.timestamp + self.block_interval;
+        if public_key.is_some() && self.proposer(previous_header.index + 1) == public_key {
+            slot
+        } else {
+            slot + self.block_interval
+        }
+    }
+
+    fn block_weight(&self, header: &BlockHeader) -> u128 {
+        match self.proposer(header.index) {
+            Some(proposer) if header.verify_signature(proposer) => 2,
+            _ => 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::{BlockHash, HashAlgorithm};
+    use crate::pow::PowFunction;
+    use crate::transaction::generate_keypair;
+
+    fn proof_of_work(adjustment_interval: usize) -> ProofOfWork {
+        ProofOfWork {
+            initial_difficulty: 8,
+            block_interval: 10,
+            adjustment_interval,
+            pow: PowParams {
+                function: PowFunction::Hash,
+                memory_kib: 0,
+                iterations: 0,
+            },
+        }
+    }
+
+    /// Headers of a chain starting at genesis, with the given timestamps and
+    /// a difficulty of 8 after genesis.
+    fn headers(timestamps: &[u64]) -> Vec<BlockHeader> {
+        timestamps
+            .iter()
+            .enumerate()
+            .map(|(index, timestamp)| BlockHeader {
+                version: 1,
+                index,
+                previous_hash: BlockHash::default(),
+                timestamp: *timestamp,
+                merkle_root: String::new(),
+                difficulty: if index == 0 { 0 } else { 8 },
+                nonce: 0,
+                hash: BlockHash::default(),
+                signature: String::new(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn starts_at_initial_difficulty() {
+        assert_eq!(proof_of_work(4).expected_difficulty(&headers(&[0])), 8);
+    }
+
+    #[test]
+    fn keeps_difficulty_between_adjustments() {
+        let chain = headers(&[0, 1000, 1001, 1002, 1003, 1004]);
+        assert_eq!(proof_of_work(4).expected_difficulty(&chain[..5]), 8);
+    }
+
+    #[test]
+    fn retargets_over_the_last_interval() {
+        let pow = proof_of_work(4);
+        // Blocks 4 to 7 follow block 3, the last one of the previous interval.
+        let fast = headers(&[0, 1000, 1010, 1020, 1030, 1031, 1032, 1033]);
+        assert_eq!(pow.expected_difficulty(&fast[3..]), 9);
+        let on_target = headers(&[0, 1000, 1010, 1020, 1030, 1040, 1050, 1070]);
+        assert_eq!(pow.expected_difficulty(&on_target[3..]), 8);
+        let slow = headers(&[0, 1000, 1010, 1020, 1030, 1100, 1200, 1300]);
+        assert_eq!(pow.expected_difficulty(&slow[3..]), 7);
+    }
+
+    #[test]
+    fn measures_first_interval_from_block_1() {
+        // Blocks came in every second, long after the genesis timestamp.
+        let chain = headers(&[0, 5000, 5001, 5002]);
+        assert_eq!(proof_of_work(4).expected_difficulty(&chain), 9);
+    }
+
+    #[test]
+    fn retargets_every_block_with_an_interval_of_1() {
+        let pow = proof_of_work(1);
+        assert_eq!(pow.expected_difficulty(&headers(&[0, 1000, 1001])[1..]), 9);
+        assert_eq!(pow.expected_difficulty(&headers(&[0, 1000, 1100])[1..]), 7);
+    }
+
+    /// Proof-of-authority over three fresh validators 10 seconds apart,
+    /// along with their secret keys.
+    fn proof_of_authority() -> (ProofOfAuthority, Vec<String>) {
+        let (validators, secret_keys) = (0..3).map(|_| generate_keypair()).unzip();
+        let consensus = ProofOfAuthority {
+            validators,
+            block_interval: 10,
+        };
+        (consensus, secret_keys)
+    }
+
+    /// Header of block 1 at `timestamp` on top of a genesis at time 0,
+    /// signed with `secret_key`.
+    fn signed_header(timestamp: u64, secret_key: &str) -> BlockHeader {
+        let mut header = headers(&[0, timestamp]).remove(1);
+        header.difficulty = 0;
+        header.hash = HashAlgorithm::Sha256.digest(timestamp.to_be_bytes());
+        header.sign(secret_key).unwrap();
+        header
+    }
+
+    #[test]
+    fn accepts_in_turn_block_after_interval() {
+        let (consensus, secret_keys) = proof_of_authority();
+        let genesis = &headers(&[0])[0];
+        let header = signed_header(10, &secret_keys[1]);
+        assert_eq!(consensus.is_valid_seal(&header, genesis), Ok(()));
+        assert_eq!(consensus.block_weight(&header), 2);
+
+        let early = signed_header(9, &secret_keys[1]);
+        assert_eq!(
+            consensus.is_valid_seal(&early, genesis),
+            Err("block 1 was proposed too early".to_string())
+        );
+    }
+
+    #[test]
+    fn accepts_out_of_turn_block_after_timeout() {
+        let (consensus, secret_keys) = proof_of_authority();
+        let genesis = &headers(&[0])[0];
+        let early = signed_header(19, &secret_keys[2]);
+        assert_eq!(
+            consensus.is_valid_seal(&early, genesis),
+            Err("block 1 was proposed too early".to_string())
+        );
+
+        let header = signed_header(20, &secret_keys[2]);
+        assert_eq!(consensus.is_valid_seal(&header, genesis), Ok(()));
+        assert_eq!(consensus.block_weight(&header), 1);
+    }
+
+    #[test]
+    fn rejects_block_not_signed_by_a_validator() {
+        let (consensus, secret_keys) = proof_of_authority();
+        let genesis = &headers(&[0])[0];
+        let (_, outsider) = generate_keypair();
+        let header = signed_header(20, &outsider);
+        assert_eq!(
+            consensus.is_valid_seal(&header, genesis),
+            Err("block 1 is not signed by a validator".to_string())
+        );
+
+        let mut tampered = signed_header(20, &secret_keys[1]);
+        tampered.hash = HashAlgorithm::Sha256.digest(b"another block");
+        assert_eq!(
+            consensus.is_valid_seal(&tampered, genesis),
+            Err("block 1 is not signed by a validator".to_string())
+        );
+    }
+
+    #[test]
+    fn lets_validators_produce_in_their_slot() {
+        let (consensus, _) = proof_of_authority();
+        let genesis = &headers(&[0])[0];
+        let in_turn = Some(consensus.validators[1].as_str());
+        let out_of_turn = Some(consensus.validators[2].as_str());
+        assert_eq!(consensus.earliest_timestamp(genesis, in_turn), 10);
+        assert_eq!(consensus.earliest_timestamp(genesis, out_of_turn), 20);
+
+        assert!(!consensus.can_produce(genesis, in_turn, 9));
+        assert!(consensus.can_produce(genesis, in_turn, 10));
+        assert!(!consensus.can_produce(genesis, out_of_turn, 10));
+        assert!(consensus.can_produce(genesis, out_of_turn, 20));
+        assert!(!consensus.can_produce(genesis, Some(&"ab".repeat(32)), 20));
+        assert!(!consensus.can_produce(genesis, None, 20));
+    }
+}
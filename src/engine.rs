@@ -1,73 +1,113 @@
+use std::collections::HashMap;
+
 use actix_web::web;
 use tokio::sync::mpsc::UnboundedReceiver;
 
 use crate::{
+    chain::{Block, BlockStatus},
     net::{P2PMessage, TransmitHandlers},
+    sync::{HeaderSync, SyncMode, MAX_BODIES, MAX_HEADERS},
     ApiState,
 };
 
+/// Number of invalid blocks in a row after which a peer is disconnected, as
+/// each of them may have cost us a memory-hard proof-of-work check.
+const MAX_INVALID_BLOCKS: u32 = 3;
+
 pub async fn handle_engine(
     shared_states: web::Data<ApiState>,
     handlers: TransmitHandlers,
-    mut rx: UnboundedReceiver<P2PMessage>,
+    mut rx: UnboundedReceiver<(P2PMessage, Option<String>)>,
+    sync_mode: SyncMode,
+    local_peer_id: String,
 ) {
-    let receiver_handler = |msg| -> () {
+    let mut sync = HeaderSync::new(local_peer_id);
+    let announce_tip = || {
+        let latest_block = shared_states
+            .chains
+            .lock()
+            .unwrap()
+            .get_latest_block()
+            .unwrap();
+        handlers
+            .swarm_tx
+            .send(P2PMessage::ResponseBlockchain(vec![latest_block]))
+            .unwrap();
+    };
+    // Invalid blocks received from each peer since its last valid one.
+    let mut invalid_blocks: HashMap<String, u32> = HashMap::new();
+    let mut receiver_handler = |msg, source: Option<String>, sync: &mut HeaderSync| -> () {
         log::info!("Sending: {:?}", msg);
         match msg {
             P2PMessage::QueryAll => {
-                let chain = shared_states.chains.lock().unwrap().chains.to_vec();
+                let chain = shared_states.chains.lock().unwrap().get_blocks();
                 handlers
                     .swarm_tx
                     .send(P2PMessage::ResponseBlockchain(chain))
                     .unwrap();
             }
-            P2PMessage::QueryLatest => {
-                let latest_block = shared_states
-                    .chains
-                    .lock()
-                    .unwrap()
-                    .get_latest_block()
-                    .unwrap()
-                    .clone();
-                handlers
-                    .swarm_tx
-                    .send(P2PMessage::ResponseBlockchain(vec![latest_block]))
-                    .unwrap();
-            }
-            P2PMessage::ResponseBlockchain(received_chain) => {
+            P2PMessage::QueryLatest => announce_tip(),
+            P2PMessage::ResponseBlockchain(mut received_chain) => {
                 let mut chains = shared_states.chains.lock().unwrap();
-                let latest_block_held = chains.get_latest_block().unwrap();
-                let lastes_block_received = received_chain.last().unwrap();
-                if lastes_block_received.index > latest_block_held.index {
-                    log::info!(
-                        "blockchain possibly behind. We got:   {} + ' Peer got: ' + {}",
-                        latest_block_held.index,
-                        lastes_block_received.index
-                    );
-                    if latest_block_held.hash == lastes_block_received.previous_hash {
-                        log::info!("We can append the received block to our chain");
-                        chains.add_block(lastes_block_received.clone());
+                let status = if received_chain.len() == 1 {
+                    chains.add_block(received_chain.pop().unwrap())
+                } else {
+                    chains.replace_block_chain(received_chain)
+                };
+                if let Some(peer) = &source {
+                    if !matches!(status, BlockStatus::Invalid(_)) {
+                        invalid_blocks.remove(peer);
+                    }
+                }
+                match status {
+                    BlockStatus::NewTip(reorg) => {
+                        log::info!(
+                            "Tip moved: {} blocks disconnected, {} blocks connected",
+                            reorg.disconnected.len(),
+                            reorg.connected.len()
+                        );
+                        shared_states.on_new_tip(&chains, &reorg);
                         let latest_block = chains.get_latest_block().unwrap();
                         handlers
                             .swarm_tx
-                            .send(P2PMessage::ResponseBlockchain(vec![latest_block.clone()]))
+                            .send(P2PMessage::ResponseBlockchain(vec![latest_block]))
                             .unwrap();
-                    } else if received_chain.len() == 1 {
-                        log::info!("We have to query the chain from our peer");
-                        handlers.swarm_tx.send(P2PMessage::QueryAll).unwrap();
-                    } else {
-                        log::info!("Received blockchain is longer than current blockchain");
-                        if chains.replace_block_chain(received_chain.clone()) {
-                            handlers
-                                .swarm_tx
-                                .send(P2PMessage::ResponseBlockchain(received_chain))
-                                .unwrap();
+                    }
+                    BlockStatus::Orphan => {
+                        drop(chains);
+                        match sync_mode {
+                            SyncMode::Full => {
+                                log::info!("We have to query the chain from our peer");
+                                handlers.swarm_tx.send(P2PMessage::QueryAll).unwrap();
+                            }
+                            SyncMode::Headers => {
+                                log::info!("We have to sync the headers from our peers");
+                                sync.start(&shared_states, None);
+                            }
                         }
                     }
-                } else {
-                    log::info!(
-                        "received blockchain is not longer than current blockchain. Do nothing"
-                    );
+                    BlockStatus::SideChain => {
+                        log::info!("received blocks were stored on a side chain. Do nothing");
+                    }
+                    BlockStatus::Known => {
+                        log::info!("received blockchain is already known. Do nothing");
+                    }
+                    BlockStatus::Invalid(e) => {
+                        log::warn!("received blockchain is invalid: {e}");
+                        drop(chains);
+                        if let Some(peer) = source {
+                            let count = invalid_blocks.entry(peer.clone()).or_default();
+                            *count += 1;
+                            if *count >= MAX_INVALID_BLOCKS {
+                                invalid_blocks.remove(&peer);
+                                sync.ban_peer(&shared_states, &peer);
+                            }
+                        }
+                    }
+                    BlockStatus::Fatal(e) => {
+                        drop(chains);
+                        crate::shut_down(&e);
+                    }
                 }
             }
             P2PMessage::QueryPeers => {
@@ -84,14 +124,76 @@ pub async fn handle_engine(
             P2PMessage::AddPeer(_) => {
                 log::info!("add peer");
                 handlers.swarm_tx.send(msg).unwrap();
-            },
+            }
+            P2PMessage::Handshake { .. } => {
+                log::info!("handshakes are answered by the swarm. Do nothing");
+            }
+            P2PMessage::BanPeer(_) => {
+                log::info!("peers are banned by the engine. Do nothing");
+            }
+            P2PMessage::PeerConnected(peer) => {
+                // Announce our tip so that whichever side is behind catches up.
+                announce_tip();
+                if sync_mode == SyncMode::Headers {
+                    sync.add_peer(&shared_states, peer);
+                }
+            }
+            P2PMessage::GetHeaders {
+                route,
+                start,
+                count,
+            } => {
+                let headers = shared_states
+                    .chains
+                    .lock()
+                    .unwrap()
+                    .headers(start, count.min(MAX_HEADERS));
+                handlers
+                    .swarm_tx
+                    .send(P2PMessage::Headers { route, headers })
+                    .unwrap();
+            }
+            P2PMessage::Headers { route, headers } => {
+                sync.on_headers(&shared_states, source, route, headers);
+            }
+            P2PMessage::GetBodies { route, hashes } => {
+                let chains = shared_states.chains.lock().unwrap();
+                let bodies = hashes
+                    .iter()
+                    .take(MAX_BODIES)
+                    .filter_map(|hash| chains.get_block(hash))
+                    .map(Block::into_body)
+                    .collect();
+                handlers
+                    .swarm_tx
+                    .send(P2PMessage::Bodies { route, bodies })
+                    .unwrap();
+            }
+            P2PMessage::Bodies { route, bodies } => {
+                sync.on_bodies(&shared_states, source, route, bodies);
+            }
+            P2PMessage::NewTransaction(transaction) => {
+                let chains = shared_states.chains.lock().unwrap();
+                let added = shared_states
+                    .mempool
+                    .lock()
+                    .unwrap()
+                    .add(transaction, chains.state());
+                match added {
+                    Ok(true) => log::info!("new transaction added to the mempool"),
+                    Ok(false) => log::info!("transaction is already pending. Do nothing"),
+                    Err(e) => log::warn!("received transaction is invalid: {e}"),
+                }
+            }
         }
     };
+    let mut requests = tokio::time::interval(tokio::time::Duration::from_secs(1));
     loop {
         tokio::select! {
-            Some(msg) = rx.recv() => {
-                receiver_handler(msg);
+            Some((msg, source)) = rx.recv() => {
+                receiver_handler(msg, source, &mut sync);
             }
+            _ = requests.tick() => sync.tick(&shared_states),
         }
     }
 }
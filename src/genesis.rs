@@ -0,0 +1,56 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::chain::{Block, ChainParams};
+use serde::{Deserialize, Serialize};
+
+/// Definition of a network: its identifier, the genesis block and the
+/// parameters every node of the network has to agree on.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Genesis {
+    /// Identifies the network; nodes of different chains never sync.
+    pub chain_id: String,
+    pub timestamp: u64,
+    /// Free-form data committed to by the genesis block.
+    #[serde(default)]
+    pub extra_data: String,
+    #[serde(default)]
+    pub params: ChainParams,
+}
+
+impl Default for Genesis {
+    fn default() -> Self {
+        Genesis {
+            chain_id: "test-net".to_string(),
+            timestamp: 1723020013,
+            extra_data: "genesis".to_string(),
+            params: ChainParams::default(),
+        }
+    }
+}
+
+impl Genesis {
+    /// Reads a genesis file in JSON format.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let data = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&data)?)
+    }
+
+    /// The genesis block commits to the whole definition through its
+    /// `previous_hash`, so that networks differing in any parameter end up
+    /// with different genesis hashes.
+    pub fn block(&self) -> Block {
+        let definition = serde_json::to_vec(self).unwrap();
+        let algorithm = self.params.hash_algorithm;
+        Block::new(
+            algorithm,
+            0,
+            algorithm.digest(definition),
+            self.timestamp,
+            &[],
+            0,
+            0,
+        )
+    }
+}
@@ -0,0 +1,204 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use sha3::Sha3_256;
+
+/// Hash function used for block hashes, chosen per network.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum HashAlgorithm {
+    #[default]
+    #[serde(rename = "sha256")]
+    #[value(name = "sha256")]
+    Sha256,
+    /// SHA-256 applied twice, as in Bitcoin.
+    #[serde(rename = "sha256d")]
+    #[value(name = "sha256d")]
+    DoubleSha256,
+    #[serde(rename = "sha3-256")]
+    #[value(name = "sha3-256")]
+    Sha3_256,
+    #[serde(rename = "blake3")]
+    #[value(name = "blake3")]
+    Blake3,
+}
+
+impl HashAlgorithm {
+    pub fn digest(self, data: impl AsRef<[u8]>) -> BlockHash {
+        let bytes = match self {
+            HashAlgorithm::Sha256 => Sha256::digest(data).into(),
+            HashAlgorithm::DoubleSha256 => Sha256::digest(Sha256::digest(data)).into(),
+            HashAlgorithm::Sha3_256 => Sha3_256::digest(data).into(),
+            HashAlgorithm::Blake3 => blake3::hash(data.as_ref()).into(),
+        };
+        BlockHash(bytes)
+    }
+}
+
+/// 32-byte digest identifying a block, computed with the [`HashAlgorithm`]
+/// of the network.
+///
+/// Human readable formats such as the JSON API show it as 64 hex characters;
+/// binary formats used on the wire and on disk carry the 32 raw bytes.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BlockHash([u8; 32]);
+
+impl BlockHash {
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// Number of zero bits the hash starts with.
+    pub fn leading_zero_bits(&self) -> u32 {
+        let mut zeros = 0;
+        for byte in self.0 {
+            zeros += byte.leading_zeros();
+            if byte != 0 {
+                break;
+            }
+        }
+        zeros
+    }
+}
+
+impl From<[u8; 32]> for BlockHash {
+    fn from(bytes: [u8; 32]) -> Self {
+        BlockHash(bytes)
+    }
+}
+
+impl fmt::Display for BlockHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+impl fmt::Debug for BlockHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{self}\"")
+    }
+}
+
+impl FromStr for BlockHash {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(s).map_err(|e| format!("invalid block hash {s:?}: {e}"))?;
+        let bytes: [u8; 32] = bytes.try_into().map_err(|bytes: Vec<u8>| {
+            format!(
+                "invalid block hash {s:?}: expected 32 bytes, got {}",
+                bytes.len()
+            )
+        })?;
+        Ok(BlockHash(bytes))
+    }
+}
+
+impl Serialize for BlockHash {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.collect_str(self)
+        } else {
+            self.0.serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for BlockHash {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            let s = String::deserialize(deserializer)?;
+            s.parse().map_err(serde::de::Error::custom)
+        } else {
+            Ok(BlockHash(<[u8; 32]>::deserialize(deserializer)?))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEX: &str = "00000fa1b2c3d4e5f60718293a4b5c6d7e8f90a1b2c3d4e5f60718293a4b5c6d";
+
+    #[test]
+    fn digests_known_vectors() {
+        let vectors = [
+            (
+                HashAlgorithm::Sha256,
+                "abc",
+                "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            ),
+            (
+                HashAlgorithm::DoubleSha256,
+                "abc",
+                "4f8b42c22dd3729b519ba6f68d2da7cc5b2d606d05daed5ad5128cc03e6c6358",
+            ),
+            (
+                HashAlgorithm::Sha3_256,
+                "abc",
+                "3a985da74fe225b2045c172d6bd390bd855f086e3e9d525b46bfe24511431532",
+            ),
+            (
+                HashAlgorithm::Blake3,
+                "",
+                "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262",
+            ),
+        ];
+        for (algorithm, input, expected) in vectors {
+            assert_eq!(
+                algorithm.digest(input).to_string(),
+                expected,
+                "{algorithm:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn serializes_as_hex_in_json() {
+        let hash: BlockHash = HEX.parse().unwrap();
+        let json = serde_json::to_string(&hash).unwrap();
+        assert_eq!(json, format!("\"{HEX}\""));
+        assert_eq!(serde_json::from_str::<BlockHash>(&json).unwrap(), hash);
+    }
+
+    #[test]
+    fn serializes_as_raw_bytes_in_bincode() {
+        let hash: BlockHash = HEX.parse().unwrap();
+        let encoded = bincode::serialize(&hash).unwrap();
+        assert_eq!(encoded, hash.as_bytes());
+        assert_eq!(bincode::deserialize::<BlockHash>(&encoded).unwrap(), hash);
+    }
+
+    #[test]
+    fn rejects_malformed_hex() {
+        assert_eq!(
+            "abc".parse::<BlockHash>().unwrap_err(),
+            "invalid block hash \"abc\": Odd number of digits"
+        );
+        assert_eq!(
+            "zz".repeat(32).parse::<BlockHash>().unwrap_err(),
+            format!(
+                "invalid block hash \"{}\": Invalid character 'z' at position 0",
+                "zz".repeat(32)
+            )
+        );
+        assert_eq!(
+            "ab".repeat(31).parse::<BlockHash>().unwrap_err(),
+            format!(
+                "invalid block hash \"{}\": expected 32 bytes, got 31",
+                "ab".repeat(31)
+            )
+        );
+        assert!(serde_json::from_str::<BlockHash>("\"abcd\"").is_err());
+        assert!(bincode::deserialize::<BlockHash>(&[0u8; 31]).is_err());
+    }
+
+    #[test]
+    fn counts_leading_zero_bits() {
+        let hash: BlockHash = HEX.parse().unwrap();
+        assert_eq!(hash.leading_zero_bits(), 20);
+        assert_eq!(BlockHash::default().leading_zero_bits(), 256);
+    }
+}
@@ -3,24 +3,131 @@ use actix_web::{
     App, HttpServer,
 };
 use api::*;
-use chain::{Block, Chain};
+use chain::{Chain, ChainParams, DEFAULT_MAX_FUTURE_DRIFT};
 use clap::Parser;
+use consensus::ConsensusMode;
+use genesis::Genesis;
+use hash::HashAlgorithm;
+use mempool::Mempool;
+use miner::MinerConfig;
 use net::{P2PMessage, ReceiveHandlers, TransmitHandlers};
+use pow::PowFunction;
+use state::LedgerMode;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use std::thread;
+use storage::{BlockStore, FileStore, MemoryStore};
+use sync::SyncMode;
 
 use tokio::sync::mpsc::unbounded_channel;
 mod api;
 mod chain;
+mod consensus;
 mod engine;
+mod genesis;
+mod hash;
+mod mempool;
+mod merkle;
+mod miner;
 mod net;
+mod pow;
+mod state;
+mod storage;
+mod sync;
+#[cfg(test)]
+mod testing;
+mod transaction;
+mod utxo;
+mod wal;
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
+#[clap(group(clap::ArgGroup::new("chain_params").multiple(true)))]
 pub struct Cli {
     #[clap(short, long, value_delimiter = ',', num_args = 1..)]
     pub list: Option<Vec<String>>,
     #[clap(short, long)]
     pub port: u16,
+    /// JSON file defining the genesis block, chain id and chain parameters
+    #[clap(long, conflicts_with = "chain_params")]
+    pub genesis: Option<PathBuf>,
+    /// Identifier of the chain, naming the gossip topic shared by its nodes
+    #[clap(long, group = "chain_params", default_value_t = Genesis::default().chain_id)]
+    pub chain_id: String,
+    /// Leading zero bits required for the first mined block
+    #[clap(long, group = "chain_params", default_value_t = ChainParams::default().initial_difficulty)]
+    pub difficulty: u32,
+    /// Target number of seconds between two blocks
+    #[clap(long, group = "chain_params", default_value_t = ChainParams::default().block_interval)]
+    pub block_interval: u64,
+    /// Number of blocks between two difficulty adjustments
+    #[clap(long, group = "chain_params", default_value_t = ChainParams::default().adjustment_interval)]
+    pub adjustment_interval: usize,
+    /// Initial balances as a comma separated list of <address>:<amount>
+    #[clap(long, group = "chain_params", value_delimiter = ',', value_parser = parse_allocation)]
+    pub allocations: Vec<(String, u64)>,
+    /// Coins created by each block and paid to its miner
+    #[clap(long, group = "chain_params", default_value_t = ChainParams::default().block_subsidy)]
+    pub block_subsidy: u64,
+    /// Halve the block subsidy every this many blocks
+    #[clap(long, group = "chain_params")]
+    pub halving_interval: Option<usize>,
+    /// Number of recent blocks whose median timestamp a new block must exceed
+    #[clap(long, group = "chain_params", default_value_t = ChainParams::default().median_time_span)]
+    pub median_time_span: usize,
+    /// Seconds a block timestamp may be ahead of the local clock
+    #[clap(long, default_value_t = DEFAULT_MAX_FUTURE_DRIFT)]
+    pub max_future_drift: u64,
+    /// Accept blocks hashed with the legacy header encoding up to this height
+    #[clap(long, group = "chain_params")]
+    pub legacy_header_height: Option<usize>,
+    /// Hash function used for block hashes
+    #[clap(long, group = "chain_params", value_enum, default_value_t = HashAlgorithm::default())]
+    pub hash_algorithm: HashAlgorithm,
+    /// Function whose output has to meet the difficulty under proof-of-work
+    #[clap(long, group = "chain_params", value_enum, default_value_t = PowFunction::default())]
+    pub pow_function: PowFunction,
+    /// Memory used by each evaluation of a memory-hard proof-of-work, in KiB
+    #[clap(long, group = "chain_params", default_value_t = ChainParams::default().pow_memory_kib)]
+    pub pow_memory_kib: u32,
+    /// Passes over that memory
+    #[clap(long, group = "chain_params", default_value_t = ChainParams::default().pow_iterations)]
+    pub pow_iterations: u32,
+    /// Address credited with the reward of the blocks mined by this node
+    #[clap(long)]
+    pub miner_address: Option<String>,
+    /// Whether transactions move account balances or unspent outputs
+    #[clap(long, group = "chain_params", value_enum, default_value_t = LedgerMode::default())]
+    pub ledger: LedgerMode,
+    /// Whether blocks are sealed with proof-of-work or signed by validators
+    #[clap(long, group = "chain_params", value_enum, default_value_t = ConsensusMode::default())]
+    pub consensus: ConsensusMode,
+    /// Public keys of the proof-of-authority validators, in turn order
+    #[clap(long, group = "chain_params", value_delimiter = ',')]
+    pub validators: Vec<String>,
+    /// Secret key signing our blocks when we are a validator
+    #[clap(long)]
+    pub validator_key: Option<String>,
+    /// Keep mining blocks in the background
+    #[clap(long)]
+    pub mine: bool,
+    /// Number of threads searching for a nonce, all cores by default
+    #[clap(long, default_value_t = thread::available_parallelism().map_or(1, usize::from))]
+    pub miner_threads: usize,
+    /// Directory where the chain is stored between restarts
+    #[clap(long)]
+    pub data_dir: Option<PathBuf>,
+    /// How to catch up with peers whose chain is ahead of ours
+    #[clap(long, value_enum, default_value_t = SyncMode::default())]
+    pub sync: SyncMode,
+}
+
+fn parse_allocation(value: &str) -> Result<(String, u64), String> {
+    let (address, amount) = value.split_once(':').ok_or("expected <address>:<amount>")?;
+    let amount = amount
+        .parse()
+        .map_err(|e| format!("invalid amount {amount}: {e}"))?;
+    Ok((address.to_string(), amount))
 }
 
 #[actix_web::main]
@@ -28,7 +135,7 @@ async fn main() {
     simple_logger::init_with_level(log::Level::Info).unwrap();
     let cli = Cli::parse();
     let (tx, rx) = unbounded_channel::<P2PMessage>();
-    let (tx_router, rx_router) = unbounded_channel::<P2PMessage>();
+    let (tx_router, rx_router) = unbounded_channel::<(P2PMessage, Option<String>)>();
     let (tx_api_peers, rx_api_peers) = unbounded_channel::<P2PMessage>();
 
     let transmit_handlers = TransmitHandlers {
@@ -38,23 +145,85 @@ async fn main() {
     };
 
     let receive_handlers = ReceiveHandlers {
-        api_peers_rx: Mutex::new(rx_api_peers),
+        api_peers_rx: tokio::sync::Mutex::new(rx_api_peers),
     };
 
-    let genesis_block: Block = Chain::get_genesis_block();
-    let chain: Chain = Chain::new(genesis_block);
+    let genesis = match &cli.genesis {
+        Some(path) => Genesis::load(path).unwrap(),
+        None => Genesis {
+            chain_id: cli.chain_id,
+            params: ChainParams {
+                initial_difficulty: cli.difficulty,
+                block_interval: cli.block_interval,
+                adjustment_interval: cli.adjustment_interval,
+                allocations: cli.allocations.into_iter().collect(),
+                ledger: cli.ledger,
+                block_subsidy: cli.block_subsidy,
+                halving_interval: cli.halving_interval,
+                consensus: cli.consensus,
+                validators: cli.validators,
+                median_time_span: cli.median_time_span,
+                legacy_header_height: cli.legacy_header_height,
+                hash_algorithm: cli.hash_algorithm,
+                pow_function: cli.pow_function,
+                pow_memory_kib: cli.pow_memory_kib,
+                pow_iterations: cli.pow_iterations,
+            },
+            ..Genesis::default()
+        },
+    };
+    let genesis_block = genesis.block();
+    log::info!(
+        "chain {} with genesis block {}",
+        genesis.chain_id,
+        genesis_block.header.hash
+    );
+    let params = genesis.params;
+    params.check().unwrap();
+    let store: Box<dyn BlockStore> = match &cli.data_dir {
+        Some(data_dir) => Box::new(FileStore::open(data_dir).unwrap()),
+        None => Box::new(MemoryStore::new()),
+    };
+    let consensus = consensus::from_params(&params);
+    let chain: Chain = Chain::new(
+        genesis_block.clone(),
+        params,
+        consensus,
+        store,
+        cli.max_future_drift,
+    )
+    .unwrap();
 
     println!("here {chain:?}");
-    let api_states: ApiState =
-        ApiState::new(Arc::new(Mutex::new(chain)), transmit_handlers.clone(), receive_handlers);
+    let api_states: ApiState = ApiState::new(
+        Arc::new(Mutex::new(chain)),
+        Arc::new(Mutex::new(Mempool::new())),
+        transmit_handlers.clone(),
+        receive_handlers,
+        MinerConfig {
+            address: cli.miner_address.clone(),
+            threads: cli.miner_threads,
+            validator_key: cli.validator_key.clone(),
+        },
+    );
     let shared_states = web::Data::new(api_states);
 
-    net::config_network(transmit_handlers.clone(), rx);
+    let local_peer_id = net::config_network(
+        genesis.chain_id.clone(),
+        genesis_block.header.hash,
+        transmit_handlers.clone(),
+        rx,
+    );
     actix_web::rt::spawn(engine::handle_engine(
         shared_states.clone(),
         transmit_handlers.clone(),
         rx_router,
+        cli.sync,
+        local_peer_id.to_string(),
     ));
+    if cli.mine {
+        actix_web::rt::spawn(miner::handle_miner(shared_states.clone()));
+    }
 
     let _ = HttpServer::new(move || {
         App::new()
@@ -62,13 +231,36 @@ async fn main() {
             .service(
                 web::scope("/blocks")
                     .route("/get", web::get().to(api_blocks))
-                    .route("/mine", web::post().to(api_mine)),
+                    .route("/mine", web::post().to(api_mine))
+                    .route("/{index}/proof/{item}", web::get().to(api_merkle_proof)),
             )
+            .route("/miner/hashrate", web::get().to(api_hashrate))
             .route("/peers", web::get().to(api_peer))
             .route("/addpeer", web::post().to(api_add_peer))
+            .service(
+                web::scope("/transactions")
+                    .route("", web::get().to(api_pending_transactions))
+                    .route("", web::post().to(api_submit_transaction))
+                    .route("/sign", web::post().to(api_sign_transaction)),
+            )
+            .route("/accounts/{address}", web::get().to(api_account))
+            .route(
+                "/accounts/{address}/unspent",
+                web::get().to(api_unspent_outputs),
+            )
+            .route("/proof/verify", web::post().to(api_verify_proof))
+            .route("/wallet/new", web::post().to(api_new_wallet))
     })
     .bind(("127.0.0.1", cli.port))
     .unwrap()
     .run()
     .await;
 }
+
+/// Stops the node after an error it cannot recover from while running.
+/// Blocks are written through the write-ahead log, so the next start
+/// recovers the stored chain.
+pub fn shut_down(reason: &str) -> ! {
+    log::error!("shutting down: {reason}");
+    std::process::exit(1);
+}
@@ -0,0 +1,190 @@
+use std::collections::HashSet;
+
+use crate::chain::Reorg;
+use crate::state::WorldState;
+use crate::transaction::Transaction;
+
+/// Maximum number of transactions a miner puts in one block.
+pub const MAX_BLOCK_TRANSACTIONS: usize = 100;
+
+/// Maximum number of pending transactions. Once reached, the transaction
+/// paying the lowest fee makes room for a better paying one.
+pub const MAX_MEMPOOL_TRANSACTIONS: usize = 5000;
+
+/// Transactions waiting to be included in a block, in arrival order, along
+/// with the fee each of them pays.
+#[derive(Debug)]
+pub struct Mempool {
+    transactions: Vec<(Transaction, u64)>,
+    ids: HashSet<String>,
+    /// Maximum number of pending transactions, [`MAX_MEMPOOL_TRANSACTIONS`].
+    capacity: usize,
+}
+
+impl Mempool {
+    pub fn new() -> Self {
+        Mempool {
+            transactions: vec![],
+            ids: HashSet::new(),
+            capacity: MAX_MEMPOOL_TRANSACTIONS,
+        }
+    }
+
+    /// Validates and queues `transaction`. Returns whether it was new to us,
+    /// in which case it should be gossiped further.
+    pub fn add(&mut self, transaction: Transaction, state: &WorldState) -> Result<bool, String> {
+        if !transaction.verify_signature() {
+            return Err("invalid transaction signature".to_string());
+        }
+        let fee = state.check_transaction(&transaction)?;
+        let id = transaction.id();
+        if self.ids.contains(&id) {
+            return Ok(false);
+        }
+        if self.transactions.len() >= self.capacity {
+            let cheapest = self.cheapest().unwrap();
+            if self.transactions[cheapest].1 >= fee {
+                return Err("mempool is full".to_string());
+            }
+            let (evicted, _) = self.transactions.remove(cheapest);
+            self.ids.remove(&evicted.id());
+        }
+        self.ids.insert(id);
+        self.transactions.push((transaction, fee));
+        Ok(true)
+    }
+
+    /// Position of the transaction paying the lowest fee, the most recent
+    /// one among equals.
+    fn cheapest(&self) -> Option<usize> {
+        self.transactions
+            .iter()
+            .enumerate()
+            .rev()
+            .min_by_key(|(_, (_, fee))| *fee)
+            .map(|(position, _)| position)
+    }
+
+    /// Transactions a miner should include in the next block.
+    pub fn pending(&self, limit: usize) -> Vec<Transaction> {
+        self.transactions
+            .iter()
+            .take(limit)
+            .map(|(transaction, _)| transaction.clone())
+            .collect()
+    }
+
+    /// Drops transactions confirmed by `reorg.connected` and puts back the
+    /// ones from `reorg.disconnected` that are no longer in the chain. Any
+    /// transaction that `state`, the ledger at the new tip, rejects is dropped,
+    /// as are the lowest paying ones beyond the capacity of the mempool.
+    pub fn apply_reorg(&mut self, reorg: &Reorg, state: &WorldState) {
+        let confirmed: HashSet<String> = reorg
+            .connected
+            .iter()
+            .flat_map(|block| block.transactions.iter().map(Transaction::id))
+            .collect();
+        let mut transactions: Vec<Transaction> = vec![];
+        for transaction in reorg.disconnected.iter().flat_map(|b| &b.transactions) {
+            let id = transaction.id();
+            if !confirmed.contains(&id) && self.ids.insert(id) {
+                transactions.push(transaction.clone());
+            }
+        }
+        transactions.extend(
+            self.transactions
+                .drain(..)
+                .map(|(transaction, _)| transaction),
+        );
+        self.transactions = transactions
+            .into_iter()
+            .filter(|transaction| !confirmed.contains(&transaction.id()))
+            .filter_map(|transaction| {
+                let fee = state.check_transaction(&transaction).ok()?;
+                Some((transaction, fee))
+            })
+            .collect();
+        while self.transactions.len() > self.capacity {
+            let cheapest = self.cheapest().unwrap();
+            self.transactions.remove(cheapest);
+        }
+        self.ids = self
+            .transactions
+            .iter()
+            .map(|(transaction, _)| transaction.id())
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain::ChainParams;
+    use crate::transaction::generate_keypair;
+
+    const CAPACITY: usize = 8;
+
+    fn transfer(secret_key: &str, nonce: u64, fee: u64) -> Transaction {
+        Transaction {
+            sender: String::new(),
+            payload: String::new(),
+            recipient: Some("bb".repeat(32)),
+            amount: 1,
+            fee,
+            inputs: vec![],
+            outputs: vec![],
+            nonce,
+            signature: String::new(),
+        }
+        .sign(secret_key)
+        .unwrap()
+    }
+
+    /// Full mempool whose first transaction pays a fee of 1 and every other
+    /// one a fee of 2, along with a funded key that sent none of them.
+    fn full_mempool() -> (Mempool, WorldState, String) {
+        let (sender, secret_key) = generate_keypair();
+        let (spare, spare_key) = generate_keypair();
+        let params = ChainParams {
+            allocations: [(sender, 1000), (spare, 1000)].into(),
+            ..ChainParams::default()
+        };
+        let state = WorldState::new(&params, "");
+        let mut mempool = Mempool {
+            capacity: CAPACITY,
+            ..Mempool::new()
+        };
+        for nonce in 0..CAPACITY as u64 {
+            let fee = if nonce == 0 { 1 } else { 2 };
+            let transaction = transfer(&secret_key, nonce, fee);
+            assert_eq!(mempool.add(transaction, &state), Ok(true));
+        }
+        (mempool, state, spare_key)
+    }
+
+    #[test]
+    fn evicts_lowest_fee_when_full() {
+        let (mut mempool, state, spare_key) = full_mempool();
+        let cheapest = mempool.pending(1).remove(0);
+
+        let better = transfer(&spare_key, 0, 3);
+        assert_eq!(mempool.add(better.clone(), &state), Ok(true));
+        let pending = mempool.pending(usize::MAX);
+        assert_eq!(pending.len(), CAPACITY);
+        assert!(!pending.contains(&cheapest));
+        assert_eq!(pending.last(), Some(&better));
+    }
+
+    #[test]
+    fn refuses_fee_not_above_the_cheapest_when_full() {
+        let (mut mempool, state, spare_key) = full_mempool();
+        let pending = mempool.pending(usize::MAX);
+
+        let equal = transfer(&spare_key, 0, 1);
+        assert_eq!(
+            mempool.add(equal, &state),
+            Err("mempool is full".to_string())
+        );
+        assert_eq!(mempool.pending(usize::MAX), pending);
+    }
+}
@@ -0,0 +1,186 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Root of an empty tree, used for blocks without transactions.
+pub const EMPTY_ROOT: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Proof that `leaf` is the item at `index` of a tree over `leaf_count`
+/// items with a given root.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MerkleProof {
+    pub leaf: String,
+    pub index: usize,
+    pub leaf_count: usize,
+    /// Sibling hashes from the leaf level up. Levels where the node has no
+    /// sibling have no step.
+    pub steps: Vec<String>,
+}
+
+/// Hash of a leaf. Leaves and inner nodes are hashed with distinct prefixes,
+/// as in RFC 6962, so that an inner node cannot pass for a leaf.
+fn hash_leaf(leaf: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update([0x00]);
+    hasher.update(leaf);
+    format!("{:x}", hasher.finalize())
+}
+
+fn hash_pair(left: &str, right: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update([0x01]);
+    hasher.update(left);
+    hasher.update(right);
+    format!("{:x}", hasher.finalize())
+}
+
+/// Hashes one level of the tree into the next. A node without a sibling is
+/// carried up unchanged rather than paired with itself, so that repeating
+/// the last leaf does not yield the same root.
+fn next_level(level: &[String]) -> Vec<String> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => hash_pair(left, right),
+            [single] => single.clone(),
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+/// Computes the Merkle root over hex encoded leaf hashes.
+pub fn merkle_root(leaves: &[String]) -> String {
+    if leaves.is_empty() {
+        return EMPTY_ROOT.to_string();
+    }
+    let mut level: Vec<String> = leaves.iter().map(|leaf| hash_leaf(leaf)).collect();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level.remove(0)
+}
+
+/// Builds the proof for the leaf at `index`, if there is one.
+pub fn merkle_proof(leaves: &[String], index: usize) -> Option<MerkleProof> {
+    let leaf = leaves.get(index)?.clone();
+    let mut steps = vec![];
+    let mut level: Vec<String> = leaves.iter().map(|leaf| hash_leaf(leaf)).collect();
+    let mut position = index;
+    while level.len() > 1 {
+        if let Some(hash) = level.get(position ^ 1) {
+            steps.push(hash.clone());
+        }
+        level = next_level(&level);
+        position /= 2;
+    }
+    Some(MerkleProof {
+        leaf,
+        index,
+        leaf_count: leaves.len(),
+        steps,
+    })
+}
+
+/// Checks that `proof` leads from its leaf up to `root`, through exactly the
+/// siblings the leaf at `proof.index` has in a tree of `proof.leaf_count`
+/// leaves.
+pub fn verify_proof(proof: &MerkleProof, root: &str) -> bool {
+    if proof.index >= proof.leaf_count {
+        return false;
+    }
+    let mut steps = proof.steps.iter();
+    let mut node = hash_leaf(&proof.leaf);
+    let mut position = proof.index;
+    let mut width = proof.leaf_count;
+    while width > 1 {
+        let sibling = position ^ 1;
+        if sibling < width {
+            let Some(hash) = steps.next() else {
+                return false;
+            };
+            node = if sibling < position {
+                hash_pair(hash, &node)
+            } else {
+                hash_pair(&node, hash)
+            };
+        }
+        position /= 2;
+        width = width.div_ceil(2);
+    }
+    steps.next().is_none() && node == root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaves(count: usize) -> Vec<String> {
+        (0..count)
+            .map(|i| format!("{:x}", Sha256::digest([i as u8])))
+            .collect()
+    }
+
+    #[test]
+    fn proves_every_leaf() {
+        for count in 1..=9 {
+            let leaves = leaves(count);
+            let root = merkle_root(&leaves);
+            for index in 0..count {
+                let proof = merkle_proof(&leaves, index).unwrap();
+                assert!(verify_proof(&proof, &root), "leaf {index} of {count}");
+            }
+            assert!(merkle_proof(&leaves, count).is_none());
+        }
+    }
+
+    #[test]
+    fn root_of_empty_tree() {
+        assert_eq!(merkle_root(&[]), EMPTY_ROOT);
+    }
+
+    #[test]
+    fn root_differs_from_repeated_last_leaf() {
+        let mut leaves = leaves(3);
+        let root = merkle_root(&leaves);
+        leaves.push(leaves[2].clone());
+        assert_ne!(merkle_root(&leaves), root);
+    }
+
+    #[test]
+    fn rejects_proof_for_another_position() {
+        let leaves = leaves(5);
+        let root = merkle_root(&leaves);
+        let mut proof = merkle_proof(&leaves, 1).unwrap();
+        proof.index = 0;
+        assert!(!verify_proof(&proof, &root));
+
+        let mut proof = merkle_proof(&leaves, 4).unwrap();
+        proof.leaf_count = 8;
+        assert!(!verify_proof(&proof, &root));
+        proof.leaf_count = 4;
+        assert!(!verify_proof(&proof, &root));
+    }
+
+    #[test]
+    fn rejects_inner_node_as_leaf() {
+        let leaves = leaves(4);
+        let root = merkle_root(&leaves);
+        let inner = hash_pair(&hash_leaf(&leaves[0]), &hash_leaf(&leaves[1]));
+        let proof = merkle_proof(&leaves, 2).unwrap();
+        let forged = MerkleProof {
+            leaf: inner,
+            index: 0,
+            leaf_count: 2,
+            steps: vec![proof.steps[1].clone()],
+        };
+        assert!(!verify_proof(&forged, &root));
+    }
+
+    #[test]
+    fn rejects_tampered_leaf() {
+        let leaves = leaves(6);
+        let root = merkle_root(&leaves);
+        let mut proof = merkle_proof(&leaves, 3).unwrap();
+        proof.leaf = leaves[2].clone();
+        assert!(!verify_proof(&proof, &root));
+    }
+}
@@ -0,0 +1,333 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use actix_web::web;
+use serde::Serialize;
+use tokio::sync::watch;
+
+use crate::{
+    chain::{
+        calculate_merkle_root, get_timestamp, header_preimage, Block, BlockStatus, BLOCK_VERSION,
+    },
+    consensus::SealKind,
+    hash::{BlockHash, HashAlgorithm},
+    mempool::MAX_BLOCK_TRANSACTIONS,
+    net::P2PMessage,
+    pow::PowParams,
+    transaction::{keypair_from_hex, Transaction},
+    ApiState,
+};
+
+/// How this node produces blocks.
+#[derive(Debug, Clone, Default)]
+pub struct MinerConfig {
+    /// Address credited with the reward of the blocks we mine.
+    pub address: Option<String>,
+    /// Number of threads searching for a nonce in each mining job.
+    pub threads: usize,
+    /// Hex encoded secret key signing our blocks in proof-of-authority mode.
+    pub validator_key: Option<String>,
+}
+
+impl MinerConfig {
+    /// Hex encoded public key matching `validator_key`.
+    pub fn validator_public_key(&self) -> Option<String> {
+        let keypair = keypair_from_hex(self.validator_key.as_deref()?).ok()?;
+        Some(hex::encode(keypair.public().to_bytes()))
+    }
+}
+
+/// Everything needed to mine the next block on top of a given tip.
+#[derive(Debug, Clone)]
+pub struct BlockTemplate {
+    pub index: usize,
+    pub previous_hash: BlockHash,
+    pub timestamp: u64,
+    pub transactions: Vec<Transaction>,
+    pub difficulty: u32,
+    pub hash_algorithm: HashAlgorithm,
+    pub pow: PowParams,
+    pub seal: SealKind,
+    /// Whether the consensus rules let us produce this block.
+    pub can_produce: bool,
+}
+
+impl BlockTemplate {
+    fn block(&self) -> Block {
+        Block::new(
+            self.hash_algorithm,
+            self.index,
+            self.previous_hash,
+            self.timestamp,
+            &self.transactions,
+            self.difficulty,
+            0,
+        )
+    }
+
+    /// Seals the template with our validator key.
+    pub fn sign(&self, secret_key: Option<&str>) -> Result<Block, String> {
+        let secret_key = secret_key.ok_or("no validator key configured")?;
+        if self.timestamp > get_timestamp() {
+            return Err(format!(
+                "block {} cannot be proposed before {}",
+                self.index, self.timestamp
+            ));
+        }
+        let mut block = self.block();
+        block.header.sign(secret_key)?;
+        log::info!("signed block {}: {}", self.index, block.header.hash);
+        Ok(block)
+    }
+
+    /// Searches for a nonce whose block hash meets the difficulty on
+    /// `threads` worker threads, worker `i` trying nonces `i`, `i + threads`,
+    /// and so on. Gives up and returns `None` once `cancel` is set.
+    pub fn mine(&self, threads: usize, cancel: &AtomicBool, stats: &MinerStats) -> Option<Block> {
+        let merkle_root = calculate_merkle_root(&self.transactions);
+        let threads = threads.max(1) as u64;
+        let found = AtomicBool::new(false);
+        let started = Instant::now();
+        let (solution, hashes) = thread::scope(|scope| {
+            let workers: Vec<_> = (0..threads)
+                .map(|first| {
+                    let (merkle_root, found) = (&merkle_root, &found);
+                    scope.spawn(move || self.search(merkle_root, first, threads, cancel, found))
+                })
+                .collect();
+            workers
+                .into_iter()
+                .map(|worker| worker.join().unwrap())
+                .fold((None, 0), |(solution, total), (found, hashes)| {
+                    (solution.or(found), total + hashes)
+                })
+        });
+        stats.record(hashes, started.elapsed());
+        let Some((nonce, hash)) = solution else {
+            log::info!(
+                "abandoned mining block {} after {hashes} attempts",
+                self.index
+            );
+            return None;
+        };
+        log::info!("mined block {} with nonce {nonce}: {hash}", self.index);
+        Some(Block::_new_with_hash(
+            self.index,
+            self.previous_hash,
+            self.timestamp,
+            &self.transactions,
+            self.difficulty,
+            nonce,
+            hash,
+        ))
+    }
+
+    /// Runs one worker until it finds a solution or another worker does.
+    /// Returns the solution, if any, and the number of hashes computed.
+    fn search(
+        &self,
+        merkle_root: &str,
+        first: u64,
+        step: u64,
+        cancel: &AtomicBool,
+        found: &AtomicBool,
+    ) -> (Option<(u64, BlockHash)>, u64) {
+        let mut nonce = first;
+        let mut hashes: u64 = 0;
+        while !cancel.load(Ordering::Relaxed) && !found.load(Ordering::Relaxed) {
+            let hash = self.hash_algorithm.digest(header_preimage(
+                BLOCK_VERSION,
+                self.index,
+                &self.previous_hash,
+                self.timestamp,
+                merkle_root,
+                self.difficulty,
+                nonce,
+            ));
+            hashes += 1;
+            if self.pow.meets(&hash, self.difficulty) {
+                found.store(true, Ordering::Relaxed);
+                return (Some((nonce, hash)), hashes);
+            }
+            let Some(next) = nonce.checked_add(step) else {
+                break;
+            };
+            nonce = next;
+        }
+        (None, hashes)
+    }
+}
+
+/// Hashes computed by this node and the time spent computing them.
+#[derive(Debug, Default)]
+pub struct MinerStats {
+    hashes: AtomicU64,
+    busy_micros: AtomicU64,
+}
+
+#[derive(Serialize)]
+pub struct HashrateSchema {
+    pub threads: usize,
+    pub hashes: u64,
+    /// Average hashes per second over the time spent mining.
+    pub hashrate: f64,
+}
+
+impl MinerStats {
+    fn record(&self, hashes: u64, elapsed: Duration) {
+        self.hashes.fetch_add(hashes, Ordering::Relaxed);
+        self.busy_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn hashrate(&self, threads: usize) -> HashrateSchema {
+        let hashes = self.hashes.load(Ordering::Relaxed);
+        let busy_micros = self.busy_micros.load(Ordering::Relaxed);
+        let hashrate = if busy_micros == 0 {
+            0.0
+        } else {
+            hashes as f64 * 1_000_000.0 / busy_micros as f64
+        };
+        HashrateSchema {
+            threads,
+            hashes,
+            hashrate,
+        }
+    }
+}
+
+/// Assembles a candidate block from the pending transactions on top of our
+/// current tip. The locks are released before returning so that proof-of-work
+/// can run without blocking the node.
+pub fn new_block_template(shared_states: &ApiState) -> BlockTemplate {
+    let chains = shared_states.chains.lock().unwrap();
+    let pending = shared_states.mempool.lock().unwrap().pending(usize::MAX);
+    let transactions = chains.block_transactions(
+        pending,
+        MAX_BLOCK_TRANSACTIONS,
+        shared_states.miner.address.as_deref(),
+    );
+    let index = chains.next_index();
+    let latest_block = chains.get_latest_block().unwrap();
+    let consensus = chains.consensus();
+    let public_key = shared_states.miner.validator_public_key();
+    let now = get_timestamp();
+    BlockTemplate {
+        index,
+        timestamp: now.max(chains.earliest_timestamp(public_key.as_deref())),
+        previous_hash: latest_block.header.hash,
+        transactions,
+        difficulty: chains.get_difficulty(),
+        hash_algorithm: chains.params.hash_algorithm,
+        pow: chains.params.pow(),
+        seal: consensus.seal_kind(),
+        can_produce: consensus.can_produce(&latest_block.header, public_key.as_deref(), now),
+    }
+}
+
+/// Adds a block we mined to our chain and broadcasts it if it became our tip.
+/// Otherwise returns why it did not.
+pub fn submit_block(shared_states: &ApiState, block: Block) -> Result<(), String> {
+    let mut chains = shared_states.chains.lock().unwrap();
+    let reorg = match chains.add_block(block.clone()) {
+        BlockStatus::NewTip(reorg) => reorg,
+        BlockStatus::Invalid(e) => {
+            log::warn!("mined block {} is invalid: {e}", block.header.index);
+            return Err(format!("mined block is invalid: {e}"));
+        }
+        BlockStatus::Fatal(e) => {
+            drop(chains);
+            crate::shut_down(&e);
+        }
+        _ => {
+            log::warn!("mined block {} did not become our tip", block.header.index);
+            return Err("our tip moved while mining".to_string());
+        }
+    };
+    shared_states.on_new_tip(&chains, &reorg);
+    if let Err(e) = shared_states
+        .transmit_handlers
+        .swarm_tx
+        .send(P2PMessage::ResponseBlockchain(vec![block]))
+    {
+        log::error!("error is {e}");
+    }
+    Ok(())
+}
+
+/// Seals `template` as the consensus rules require: by searching for a nonce
+/// on the blocking thread pool, by signing it with our validator key, or not
+/// at all. Returns `Ok(None)` if mining was cancelled.
+pub async fn seal_block(
+    shared_states: web::Data<ApiState>,
+    template: BlockTemplate,
+    cancel: Arc<AtomicBool>,
+) -> Result<Option<Block>, String> {
+    if !template.can_produce {
+        return Err(format!("block {} is not ours to produce", template.index));
+    }
+    match template.seal {
+        SealKind::None => Ok(Some(template.block())),
+        SealKind::Signature => template
+            .sign(shared_states.miner.validator_key.as_deref())
+            .map(Some),
+        SealKind::ProofOfWork => tokio::task::spawn_blocking(move || {
+            template.mine(
+                shared_states.miner.threads,
+                &cancel,
+                &shared_states.miner_stats,
+            )
+        })
+        .await
+        .map_err(|e| e.to_string()),
+    }
+}
+
+/// Mines blocks in the background for as long as the node runs. Work on a
+/// candidate is abandoned as soon as our tip moves, in which case a new
+/// candidate is assembled on top of the new tip.
+pub async fn handle_miner(shared_states: web::Data<ApiState>) {
+    log::info!("miner task is started");
+    let mut tip_rx = shared_states.tip_tx.subscribe();
+    loop {
+        tip_rx.mark_unchanged();
+        let template = new_block_template(&shared_states);
+        if template.transactions.is_empty() || !template.can_produce {
+            // Nothing to confirm and nobody to reward, or not our turn:
+            // wait for the mempool to fill up or for another block to arrive.
+            wait_for_work(&mut tip_rx).await;
+            continue;
+        }
+
+        let cancel = Arc::new(AtomicBool::new(false));
+        let sealing = seal_block(shared_states.clone(), template, cancel.clone());
+        tokio::pin!(sealing);
+        tokio::select! {
+            sealed = &mut sealing => match sealed {
+                Ok(Some(block)) => {
+                    let _ = submit_block(&shared_states, block);
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    log::debug!("could not seal a block: {e}");
+                    wait_for_work(&mut tip_rx).await;
+                }
+            },
+            _ = tip_rx.changed() => {
+                log::info!("tip moved, restarting the miner");
+                cancel.store(true, Ordering::Relaxed);
+                let _ = sealing.await;
+            }
+        }
+    }
+}
+
+/// Returns once our tip moves or after a second, whichever comes first.
+async fn wait_for_work(tip_rx: &mut watch::Receiver<BlockHash>) {
+    tokio::select! {
+        _ = tip_rx.changed() => {}
+        _ = tokio::time::sleep(tokio::time::Duration::from_secs(1)) => {}
+    }
+}
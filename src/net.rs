@@ -11,10 +11,26 @@ use std::borrow::BorrowMut;
 use std::hash::{DefaultHasher, Hash, Hasher};
 use std::io;
 use std::str::FromStr;
-use std::sync::Mutex;
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::sync::Mutex;
 
-use crate::chain::Block;
+use crate::chain::{Block, BlockBody, BlockHeader};
+use crate::hash::BlockHash;
+use crate::transaction::Transaction;
+
+/// Largest message gossipsub accepts, enough for a batch of headers or bodies
+/// requested during sync.
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// Addresses a request to a single peer over the shared topic, and its answer
+/// back to the requester. `id` tells the requests of a peer apart, which also
+/// keeps gossipsub from dropping a retried request as a duplicate.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    pub id: u64,
+    pub from: String,
+    pub to: String,
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum P2PMessage {
@@ -24,6 +40,91 @@ pub enum P2PMessage {
     QueryPeers,
     ResponsePeers(Vec<PeerId>),
     AddPeer(String),
+    NewTransaction(Transaction),
+    /// Sent to `peer_id` when it subscribes to our topic so that both sides
+    /// can check they follow the same chain before syncing.
+    Handshake {
+        peer_id: String,
+        genesis_hash: BlockHash,
+    },
+    /// Sent by the swarm to the engine once the handshake with a peer
+    /// succeeded.
+    PeerConnected(String),
+    /// Sent by the engine to the swarm to disconnect a peer that sent us
+    /// invalid blocks.
+    BanPeer(String),
+    /// Asks for the headers of up to `count` canonical blocks from index
+    /// `start`.
+    GetHeaders {
+        route: Route,
+        start: usize,
+        count: usize,
+    },
+    /// Answers `GetHeaders`, in chain order.
+    Headers {
+        route: Route,
+        headers: Vec<BlockHeader>,
+    },
+    /// Asks for the bodies of the blocks with the given hashes.
+    GetBodies {
+        route: Route,
+        hashes: Vec<BlockHash>,
+    },
+    /// Answers `GetBodies` with the bodies the peer has.
+    Bodies {
+        route: Route,
+        bodies: Vec<BlockBody>,
+    },
+}
+
+impl P2PMessage {
+    /// Whether a message received over the topic concerns `peer_id`: requests
+    /// are only answered by the peer they are addressed to, and answers only
+    /// read by the peer that asked.
+    fn is_for(&self, peer_id: &str) -> bool {
+        match self {
+            P2PMessage::GetHeaders { route, .. } | P2PMessage::GetBodies { route, .. } => {
+                route.to == peer_id
+            }
+            P2PMessage::Headers { route, .. } | P2PMessage::Bodies { route, .. } => {
+                route.from == peer_id
+            }
+            _ => true,
+        }
+    }
+}
+
+/// What goes over the wire, bincode encoded: a message tagged with the chain
+/// it belongs to, so that nodes of independent chains sharing a network
+/// ignore each other.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Envelope {
+    pub chain_id: String,
+    pub message: P2PMessage,
+}
+
+/// Gossipsub topic shared by the nodes of chain `chain_id`.
+pub fn topic_name(chain_id: &str) -> String {
+    format!("naivechain/{chain_id}")
+}
+
+fn publish(
+    swarm: &mut Swarm<P2PNetWorkBehaviour>,
+    topic: &gossipsub::IdentTopic,
+    chain_id: &str,
+    message: P2PMessage,
+) {
+    let envelope = Envelope {
+        chain_id: chain_id.to_string(),
+        message,
+    };
+    if let Err(e) = swarm
+        .behaviour_mut()
+        .gossipsub
+        .publish(topic.clone(), bincode::serialize(&envelope).unwrap())
+    {
+        log::error!("Publish error: {e:?}");
+    }
 }
 
 // We create a custom network behaviour that combines Gossipsub and Mdns.
@@ -36,7 +137,9 @@ pub struct P2PNetWorkBehaviour {
 #[derive(Clone)]
 pub struct TransmitHandlers {
     pub swarm_tx: UnboundedSender<P2PMessage>,
-    pub router_tx: UnboundedSender<P2PMessage>,
+    /// Messages for the engine, along with the peer that authored them when
+    /// they come from the network.
+    pub router_tx: UnboundedSender<(P2PMessage, Option<String>)>,
     pub api_peers_tx: UnboundedSender<P2PMessage>,
 }
 pub struct ReceiveHandlers {
@@ -46,6 +149,8 @@ pub struct ReceiveHandlers {
 pub async fn handle_swarm(
     mut swarm: Swarm<P2PNetWorkBehaviour>,
     topic: gossipsub::IdentTopic,
+    chain_id: String,
+    genesis_hash: BlockHash,
     transmit_handler: TransmitHandlers,
     mut rx: UnboundedReceiver<P2PMessage>,
 ) {
@@ -57,13 +162,24 @@ pub async fn handle_swarm(
                 .behaviour_mut()
                 .gossipsub
                 .peer_protocol()
-                .map(|x| x.0.clone())
+                .map(|x| *x.0)
                 .collect::<Vec<_>>();
             transmit_handler
                 .api_peers_tx
                 .send(P2PMessage::ResponsePeers(peers))
                 .unwrap();
         }
+        P2PMessage::BanPeer(peer) => match PeerId::from_str(&peer) {
+            Ok(peer_id) => {
+                log::warn!("disconnecting {peer_id}");
+                swarm.behaviour_mut().gossipsub.blacklist_peer(&peer_id);
+                swarm
+                    .behaviour_mut()
+                    .gossipsub
+                    .remove_explicit_peer(&peer_id);
+            }
+            Err(e) => log::warn!("wrong peer id {e}"),
+        },
         P2PMessage::AddPeer(peer) => {
             match &Multiaddr::from_str(&peer) {
                 Ok(addr) => {
@@ -83,12 +199,7 @@ pub async fn handle_swarm(
         }
         _ => {
             log::info!("Sending: {:?}", msg);
-            if let Err(e) = swarm.behaviour_mut().gossipsub.publish(
-                topic.clone(),
-                serde_json::to_string::<P2PMessage>(&msg).unwrap(),
-            ) {
-                log::error!("Publish error: {e:?}");
-            }
+            publish(swarm, &topic, &chain_id, msg);
         }
     };
 
@@ -115,23 +226,66 @@ pub async fn handle_swarm(
                     topic: r_topic,
                 })) => {
                     log::info!("peer id {peer_id} subscribed to {r_topic}");
-                    if let Err(e) = swarm
-                        .behaviour_mut().gossipsub
-                        .publish(topic.clone(), serde_json::to_string::<P2PMessage>(&P2PMessage::QueryLatest).unwrap()) {
-                            log::error!("Publish error: {e:?}");
-                        }
+                    if r_topic != topic.hash() {
+                        // The peer follows another chain.
+                        continue;
+                    }
+                    let handshake = P2PMessage::Handshake {
+                        peer_id: peer_id.to_string(),
+                        genesis_hash,
+                    };
+                    publish(&mut swarm, &topic, &chain_id, handshake);
                 }
                 SwarmEvent::Behaviour(P2PNetWorkBehaviourEvent::Gossipsub(gossipsub::Event::Message {
                     propagation_source: peer_id,
                     message_id: id,
                     message,
                 })) => {
-                    let msg = String::from_utf8_lossy(&message.data);
+                    let envelope = match bincode::deserialize::<Envelope>(&message.data) {
+                        Ok(envelope) => envelope,
+                        Err(e) => {
+                            log::warn!("dropping malformed message from {peer_id}: {e}");
+                            continue;
+                        }
+                    };
                     log::info!(
-                        "Got message: '{}' with id: {id} from peer: {peer_id}",
-                        msg,
+                        "Got message: '{:?}' with id: {id} from peer: {peer_id}",
+                        envelope.message,
                     );
-                    transmit_handler.router_tx.send(serde_json::from_str::<P2PMessage>(&msg).unwrap()).unwrap();
+                    if envelope.chain_id != chain_id {
+                        log::warn!(
+                            "dropping message for chain {} from {peer_id}, we follow {chain_id}",
+                            envelope.chain_id
+                        );
+                        continue;
+                    }
+                    if !envelope.message.is_for(&swarm.local_peer_id().to_string()) {
+                        continue;
+                    }
+                    match envelope.message {
+                        P2PMessage::Handshake { peer_id: recipient, genesis_hash: their_genesis } => {
+                            let Some(author) = message.source else {
+                                continue;
+                            };
+                            if their_genesis != genesis_hash {
+                                log::warn!(
+                                    "peer {author} follows a chain with genesis {their_genesis}, ours is {genesis_hash}. Disconnecting"
+                                );
+                                swarm.behaviour_mut().gossipsub.blacklist_peer(&author);
+                                swarm.behaviour_mut().gossipsub.remove_explicit_peer(&author);
+                            } else if recipient == swarm.local_peer_id().to_string() {
+                                log::info!("handshake with {author} succeeded");
+                                transmit_handler
+                                    .router_tx
+                                    .send((P2PMessage::PeerConnected(author.to_string()), None))
+                                    .unwrap();
+                            }
+                        }
+                        msg => {
+                            let author = message.source.map(|author| author.to_string());
+                            transmit_handler.router_tx.send((msg, author)).unwrap();
+                        }
+                    }
                     },
                 SwarmEvent::NewListenAddr { address, .. } => {
                     log::info!("Local node is listening on {address}");
@@ -142,7 +296,13 @@ pub async fn handle_swarm(
     }
 }
 
-pub fn config_network(transmit_handler: TransmitHandlers, rx: UnboundedReceiver<P2PMessage>) {
+/// Starts the swarm task and returns our peer id.
+pub fn config_network(
+    chain_id: String,
+    genesis_hash: BlockHash,
+    transmit_handler: TransmitHandlers,
+    rx: UnboundedReceiver<P2PMessage>,
+) -> PeerId {
     let mut swarm = libp2p::SwarmBuilder::with_new_identity()
         .with_tokio()
         .with_tcp(
@@ -154,8 +314,11 @@ pub fn config_network(transmit_handler: TransmitHandlers, rx: UnboundedReceiver<
         .with_quic()
         .with_behaviour(|key| {
             // To content-address message, we can take the hash of message and use it as an ID.
+            // The author is hashed too: peers answering the same request, or greeting the
+            // same new peer, send identical messages that must not be dropped as duplicates.
             let message_id_fn = |message: &gossipsub::Message| {
                 let mut s = DefaultHasher::new();
+                message.source.hash(&mut s);
                 message.data.hash(&mut s);
                 gossipsub::MessageId::from(s.finish().to_string())
             };
@@ -164,9 +327,10 @@ pub fn config_network(transmit_handler: TransmitHandlers, rx: UnboundedReceiver<
             let gossipsub_config = gossipsub::ConfigBuilder::default()
                 .heartbeat_interval(tokio::time::Duration::from_secs(10)) // This is set to aid debugging by not cluttering the log space
                 .validation_mode(gossipsub::ValidationMode::Strict) // This sets the kind of message validation. The default is Strict (enforce message signing)
-                .message_id_fn(message_id_fn) // content-address messages. No two messages of the same content will be propagated.
+                .message_id_fn(message_id_fn) // content-address messages. No two messages of the same content and author will be propagated.
+                .max_transmit_size(MAX_MESSAGE_SIZE)
                 .build()
-                .map_err(|msg| io::Error::new(io::ErrorKind::Other, msg))?; // Temporary hack because `build` does not return a proper `std::error::Error`.
+                .map_err(io::Error::other)?; // Temporary hack because `build` does not return a proper `std::error::Error`.
 
             // build a gossipsub network behaviour
             let gossipsub = gossipsub::Behaviour::new(
@@ -182,7 +346,8 @@ pub fn config_network(transmit_handler: TransmitHandlers, rx: UnboundedReceiver<
         .with_swarm_config(|c| c.with_idle_connection_timeout(tokio::time::Duration::from_secs(60)))
         .build();
 
-    let topic = gossipsub::IdentTopic::new("test-net");
+    let local_peer_id = *swarm.local_peer_id();
+    let topic = gossipsub::IdentTopic::new(topic_name(&chain_id));
     // subscribes to our topic
     swarm.behaviour_mut().gossipsub.subscribe(&topic).unwrap();
 
@@ -197,5 +362,13 @@ pub fn config_network(transmit_handler: TransmitHandlers, rx: UnboundedReceiver<
         .listen_on("/ip4/0.0.0.0/tcp/0".parse().unwrap())
         .unwrap();
 
-    actix_web::rt::spawn(handle_swarm(swarm, topic, transmit_handler, rx));
+    actix_web::rt::spawn(handle_swarm(
+        swarm,
+        topic,
+        chain_id,
+        genesis_hash,
+        transmit_handler,
+        rx,
+    ));
+    local_peer_id
 }
@@ -0,0 +1,162 @@
+use argon2::{Algorithm, Argon2, Params, Version};
+use serde::{Deserialize, Serialize};
+
+use crate::chain::hash_matches_difficulty;
+use crate::hash::BlockHash;
+
+/// Upper bounds on the memory-hard function parameters, so that verifying a
+/// block stays cheap enough to validate long chains.
+pub const MAX_POW_MEMORY_KIB: u32 = 64 * 1024;
+pub const MAX_POW_ITERATIONS: u32 = 4;
+
+/// Maximum number of headers a node verifies per message under a memory-hard
+/// function, so that a sync does not hold the chain for long.
+pub const MAX_MEMORY_HARD_HEADERS: usize = 32;
+
+/// Salt of the memory-hard function. Every block hash is unique already, so a
+/// constant salt only serves to separate this use of Argon2 from others.
+const POW_SALT: &[u8] = b"naivechain-pow";
+
+/// Function whose output has to meet the difficulty under proof-of-work.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum PowFunction {
+    /// The block hash itself has to start with `difficulty` zero bits.
+    #[default]
+    #[serde(rename = "hash")]
+    #[value(name = "hash")]
+    Hash,
+    /// Argon2id of the block hash has to start with `difficulty` zero bits,
+    /// so that every attempt needs `memory_kib` of memory.
+    #[serde(rename = "argon2id")]
+    #[value(name = "argon2id")]
+    Argon2id,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PowParams {
+    pub function: PowFunction,
+    /// Memory used by each Argon2id evaluation, in KiB.
+    pub memory_kib: u32,
+    /// Number of passes over that memory.
+    pub iterations: u32,
+}
+
+impl PowParams {
+    /// Rejects parameters that would make verification too costly or that
+    /// Argon2 does not support.
+    pub fn check(&self) -> Result<(), String> {
+        if self.function == PowFunction::Hash {
+            return Ok(());
+        }
+        if self.memory_kib > MAX_POW_MEMORY_KIB {
+            return Err(format!(
+                "proof-of-work memory of {} KiB exceeds the maximum of {MAX_POW_MEMORY_KIB} KiB",
+                self.memory_kib
+            ));
+        }
+        if self.iterations > MAX_POW_ITERATIONS {
+            return Err(format!(
+                "{} proof-of-work iterations exceed the maximum of {MAX_POW_ITERATIONS}",
+                self.iterations
+            ));
+        }
+        self.argon2().map(|_| ())
+    }
+
+    fn argon2(&self) -> Result<Argon2<'static>, String> {
+        let params = Params::new(self.memory_kib, self.iterations, 1, Some(32))
+            .map_err(|e| format!("invalid proof-of-work parameters: {e}"))?;
+        Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+    }
+
+    /// Whether the block with the given hash meets `difficulty`.
+    pub fn meets(&self, block_hash: &BlockHash, difficulty: u32) -> bool {
+        match self.function {
+            PowFunction::Hash => hash_matches_difficulty(block_hash, difficulty),
+            PowFunction::Argon2id => {
+                hash_matches_difficulty(&self.argon2id(block_hash), difficulty)
+            }
+        }
+    }
+
+    fn argon2id(&self, block_hash: &BlockHash) -> BlockHash {
+        let mut out = [0u8; 32];
+        self.argon2()
+            .unwrap()
+            .hash_password_into(block_hash.as_bytes(), POW_SALT, &mut out)
+            .unwrap();
+        out.into()
+    }
+
+    /// Maximum number of headers to verify per message.
+    pub fn max_verified_headers(&self) -> usize {
+        match self.function {
+            PowFunction::Hash => usize::MAX,
+            PowFunction::Argon2id => MAX_MEMORY_HARD_HEADERS,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::HashAlgorithm;
+
+    fn argon2id_params() -> PowParams {
+        PowParams {
+            function: PowFunction::Argon2id,
+            memory_kib: 8,
+            iterations: 1,
+        }
+    }
+
+    #[test]
+    fn checks_the_block_hash_itself() {
+        let params = PowParams {
+            function: PowFunction::Hash,
+            memory_kib: 0,
+            iterations: 0,
+        };
+        let hash: BlockHash = format!("0f{}", "ff".repeat(31)).parse().unwrap();
+        assert!(params.meets(&hash, 4));
+        assert!(!params.meets(&hash, 5));
+    }
+
+    #[test]
+    fn checks_only_the_argon2id_output() {
+        let params = argon2id_params();
+        let hash = (0u64..)
+            .map(|nonce| HashAlgorithm::Sha256.digest(nonce.to_be_bytes()))
+            .find(|hash| params.meets(hash, 4))
+            .unwrap();
+        let zeros = params.argon2id(&hash).leading_zero_bits();
+        assert!(zeros >= 4);
+        assert!(params.meets(&hash, zeros));
+        assert!(!params.meets(&hash, zeros + 1));
+
+        // A block hash full of zero bits does not help.
+        let zero_hash = BlockHash::default();
+        let zeros = params.argon2id(&zero_hash).leading_zero_bits();
+        assert!(!params.meets(&zero_hash, zeros + 1));
+    }
+
+    #[test]
+    fn rejects_costly_or_invalid_parameters() {
+        let params = PowParams {
+            memory_kib: MAX_POW_MEMORY_KIB + 1,
+            ..argon2id_params()
+        };
+        assert!(params.check().is_err());
+        let params = PowParams {
+            iterations: MAX_POW_ITERATIONS + 1,
+            ..argon2id_params()
+        };
+        assert!(params.check().is_err());
+        let params = PowParams {
+            memory_kib: 1,
+            ..argon2id_params()
+        };
+        assert!(params.check().is_err());
+        assert_eq!(argon2id_params().check(), Ok(()));
+    }
+}
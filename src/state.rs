@@ -0,0 +1,390 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::chain::{Block, ChainParams};
+use crate::transaction::Transaction;
+use crate::utxo::{OutPoint, TxOutput, UnspentOutput, UtxoSet};
+
+/// How far ahead of its account nonce a transaction may be while waiting
+/// for the transactions before it.
+pub const MAX_NONCE_GAP: u64 = 64;
+
+/// How value is tracked by the ledger.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LedgerMode {
+    /// Balances and nonces per account, moved with `recipient` and `amount`.
+    #[default]
+    Account,
+    /// Bitcoin style unspent outputs, moved with `inputs` and `outputs`.
+    Utxo,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Account {
+    pub balance: u64,
+    /// Nonce the next transaction sent from this account must carry.
+    pub nonce: u64,
+}
+
+/// Previous value of an entry of the ledger.
+#[derive(Debug, Clone)]
+pub enum StateChange {
+    Account(String, Option<Account>),
+    Output(OutPoint, Option<TxOutput>),
+}
+
+/// Previous value of every entry touched by a block, in the order they
+/// were modified, so that the block can be reverted on a reorg.
+pub type BlockUndo = Vec<StateChange>;
+
+/// Ledger obtained by applying the canonical chain in order on top of the
+/// genesis allocations. Only the side matching `mode` is ever populated.
+#[derive(Debug, Clone, Default)]
+pub struct WorldState {
+    mode: LedgerMode,
+    accounts: HashMap<String, Account>,
+    utxos: UtxoSet,
+}
+
+impl WorldState {
+    pub fn new(params: &ChainParams, genesis_hash: &str) -> Self {
+        if params.ledger == LedgerMode::Utxo {
+            return WorldState {
+                mode: LedgerMode::Utxo,
+                accounts: HashMap::new(),
+                utxos: UtxoSet::new(genesis_hash, &params.allocations),
+            };
+        }
+        let accounts = params
+            .allocations
+            .iter()
+            .map(|(address, balance)| {
+                let account = Account {
+                    balance: *balance,
+                    nonce: 0,
+                };
+                (address.clone(), account)
+            })
+            .collect();
+        WorldState {
+            mode: LedgerMode::Account,
+            accounts,
+            utxos: UtxoSet::default(),
+        }
+    }
+
+    /// In UTXO mode the balance is the sum of the unspent outputs of
+    /// `address` and the nonce is unused.
+    pub fn get_account(&self, address: &str) -> Account {
+        match self.mode {
+            LedgerMode::Account => self.accounts.get(address).cloned().unwrap_or_default(),
+            LedgerMode::Utxo => Account {
+                balance: self.utxos.balance(address),
+                nonce: 0,
+            },
+        }
+    }
+
+    pub fn unspent_outputs(&self, address: &str) -> Vec<UnspentOutput> {
+        self.utxos.unspent(address)
+    }
+
+    /// Checks a transaction against the current state without applying it
+    /// and returns the fee it pays. Nonces up to [`MAX_NONCE_GAP`] ahead of
+    /// the account are accepted as they may become valid.
+    pub fn check_transaction(&self, transaction: &Transaction) -> Result<u64, String> {
+        if transaction.is_coinbase() {
+            return Err("coinbase transaction must come first in its block".to_string());
+        }
+        match self.mode {
+            LedgerMode::Account => {
+                if !transaction.inputs.is_empty() || !transaction.outputs.is_empty() {
+                    return Err("inputs and outputs are not allowed in account mode".to_string());
+                }
+            }
+            LedgerMode::Utxo => {
+                if transaction.recipient.is_some() || transaction.amount > 0 {
+                    return Err("recipient and amount are not allowed in UTXO mode".to_string());
+                }
+                if transaction.fee > 0 {
+                    return Err("fees are implicit in UTXO mode".to_string());
+                }
+                return self.utxos.check_transaction(transaction);
+            }
+        }
+        let sender = self.get_account(&transaction.sender);
+        if transaction.nonce < sender.nonce {
+            return Err(format!("nonce {} was already used", transaction.nonce));
+        }
+        if transaction.nonce - sender.nonce > MAX_NONCE_GAP {
+            return Err(format!(
+                "nonce {} is too far ahead of the account nonce {}",
+                transaction.nonce, sender.nonce
+            ));
+        }
+        if transaction.amount > 0 && transaction.recipient.is_none() {
+            return Err("amount sent without recipient".to_string());
+        }
+        let total = transaction
+            .amount
+            .checked_add(transaction.fee)
+            .ok_or("amount and fee overflow")?;
+        if sender.balance < total {
+            return Err("insufficient balance".to_string());
+        }
+        Ok(transaction.fee)
+    }
+
+    /// Applies every transaction of `block`, leaving the state untouched if
+    /// any of them is invalid. A leading coinbase transaction may claim at
+    /// most `subsidy` plus the fees paid by the rest of the block.
+    pub fn apply_block(&mut self, block: &Block, subsidy: u64) -> Result<BlockUndo, String> {
+        let mut undo: BlockUndo = vec![];
+        match self.apply_block_transactions(block, subsidy, &mut undo) {
+            Ok(()) => Ok(undo),
+            Err(e) => {
+                self.revert_block(undo);
+                Err(e)
+            }
+        }
+    }
+
+    fn apply_block_transactions(
+        &mut self,
+        block: &Block,
+        subsidy: u64,
+        undo: &mut BlockUndo,
+    ) -> Result<(), String> {
+        let (coinbase, transactions) = match block.transactions.split_first() {
+            Some((first, rest)) if first.is_coinbase() => (Some(first), rest),
+            _ => (None, &block.transactions[..]),
+        };
+        let mut fees: u64 = 0;
+        for transaction in transactions {
+            let (transaction_undo, fee) = self.apply_transaction(transaction)?;
+            undo.extend(transaction_undo);
+            fees = fees.checked_add(fee).ok_or("block fees overflow")?;
+        }
+        if let Some(coinbase) = coinbase {
+            let reward = subsidy.checked_add(fees).ok_or("block reward overflow")?;
+            self.apply_coinbase(coinbase, block.header.index, reward, undo)?;
+        }
+        Ok(())
+    }
+
+    /// Credits the outputs of `coinbase`, which must not pay out more than
+    /// `reward`. Its nonce must be the index of its block, so that no two
+    /// coinbase transactions share an id.
+    fn apply_coinbase(
+        &mut self,
+        coinbase: &Transaction,
+        index: usize,
+        reward: u64,
+        undo: &mut BlockUndo,
+    ) -> Result<(), String> {
+        if !coinbase.inputs.is_empty() || coinbase.fee > 0 {
+            return Err("coinbase transaction cannot spend coins".to_string());
+        }
+        if coinbase.nonce != index as u64 {
+            return Err(format!(
+                "coinbase nonce {} does not match the block index {index}",
+                coinbase.nonce
+            ));
+        }
+        let paid = match self.mode {
+            LedgerMode::Account => {
+                if !coinbase.outputs.is_empty() {
+                    return Err("outputs are not allowed in account mode".to_string());
+                }
+                coinbase.amount
+            }
+            LedgerMode::Utxo => {
+                if coinbase.recipient.is_some() || coinbase.amount > 0 {
+                    return Err("recipient and amount are not allowed in UTXO mode".to_string());
+                }
+                coinbase
+                    .outputs
+                    .iter()
+                    .try_fold(0u64, |total, output| total.checked_add(output.amount))
+                    .ok_or("coinbase output total overflow")?
+            }
+        };
+        if paid > reward {
+            return Err(format!(
+                "coinbase pays {paid}, more than the block reward of {reward}"
+            ));
+        }
+        match self.mode {
+            LedgerMode::Account => match &coinbase.recipient {
+                Some(recipient) => self.credit(recipient, coinbase.amount, undo),
+                None if coinbase.amount > 0 => Err("amount sent without recipient".to_string()),
+                None => Ok(()),
+            },
+            LedgerMode::Utxo => {
+                let outputs_undo = self.utxos.add_outputs(coinbase)?;
+                undo.extend(
+                    outputs_undo
+                        .into_iter()
+                        .map(|(out_point, output)| StateChange::Output(out_point, output)),
+                );
+                Ok(())
+            }
+        }
+    }
+
+    pub fn revert_block(&mut self, undo: BlockUndo) {
+        for change in undo.into_iter().rev() {
+            match change {
+                StateChange::Account(address, Some(account)) => {
+                    self.accounts.insert(address, account);
+                }
+                StateChange::Account(address, None) => {
+                    self.accounts.remove(&address);
+                }
+                StateChange::Output(out_point, output) => {
+                    self.utxos.revert(vec![(out_point, output)]);
+                }
+            }
+        }
+    }
+
+    /// Applies a single transaction, leaving the state untouched on error.
+    /// Returns the undo data along with the fee paid.
+    pub fn apply_transaction(
+        &mut self,
+        transaction: &Transaction,
+    ) -> Result<(BlockUndo, u64), String> {
+        let fee = self.check_transaction(transaction)?;
+        if self.mode == LedgerMode::Utxo {
+            let undo = self.utxos.apply_transaction(transaction)?;
+            let undo = undo
+                .into_iter()
+                .map(|(out_point, output)| StateChange::Output(out_point, output))
+                .collect();
+            return Ok((undo, fee));
+        }
+        let sender = self.get_account(&transaction.sender);
+        if transaction.nonce != sender.nonce {
+            return Err(format!(
+                "invalid nonce {}, expected {}",
+                transaction.nonce, sender.nonce
+            ));
+        }
+        let mut undo: BlockUndo = vec![];
+        self.set_account(
+            &transaction.sender,
+            Account {
+                balance: sender.balance - transaction.amount - fee,
+                nonce: sender.nonce + 1,
+            },
+            &mut undo,
+        );
+        if let Some(recipient) = &transaction.recipient {
+            if let Err(e) = self.credit(recipient, transaction.amount, &mut undo) {
+                self.revert_block(undo);
+                return Err(e);
+            }
+        }
+        Ok((undo, fee))
+    }
+
+    fn credit(&mut self, address: &str, amount: u64, undo: &mut BlockUndo) -> Result<(), String> {
+        let mut account = self.get_account(address);
+        account.balance = account
+            .balance
+            .checked_add(amount)
+            .ok_or("recipient balance overflow")?;
+        self.set_account(address, account, undo);
+        Ok(())
+    }
+
+    fn set_account(&mut self, address: &str, account: Account, undo: &mut BlockUndo) {
+        let previous = self.accounts.insert(address.to_string(), account);
+        undo.push(StateChange::Account(address.to_string(), previous));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::genesis::Genesis;
+    use crate::testing;
+
+    const SUBSIDY: u64 = 50;
+
+    fn miner() -> String {
+        "aa".repeat(32)
+    }
+
+    fn state(ledger: LedgerMode) -> WorldState {
+        let params = ChainParams {
+            ledger,
+            ..ChainParams::default()
+        };
+        WorldState::new(&params, "genesis")
+    }
+
+    fn coinbase_block(index: usize, reward: u64, ledger: LedgerMode) -> Block {
+        let mut parent = Genesis::default().block();
+        parent.header.index = index - 1;
+        let coinbase = Transaction::coinbase(index, &miner(), reward, ledger);
+        testing::block(&parent, &[coinbase], 0)
+    }
+
+    #[test]
+    fn credits_coinbase_up_to_the_reward() {
+        for ledger in [LedgerMode::Account, LedgerMode::Utxo] {
+            let mut state = state(ledger);
+            let undo = state
+                .apply_block(&coinbase_block(1, SUBSIDY, ledger), SUBSIDY)
+                .unwrap();
+            assert_eq!(state.get_account(&miner()).balance, SUBSIDY);
+            state.revert_block(undo);
+            assert_eq!(state.get_account(&miner()).balance, 0);
+        }
+    }
+
+    #[test]
+    fn rejects_coinbase_overpaying_its_reward() {
+        for ledger in [LedgerMode::Account, LedgerMode::Utxo] {
+            let mut state = state(ledger);
+            let block = coinbase_block(1, SUBSIDY + 1, ledger);
+            assert_eq!(
+                state.apply_block(&block, SUBSIDY).unwrap_err(),
+                "coinbase pays 51, more than the block reward of 50"
+            );
+            assert_eq!(state.get_account(&miner()).balance, 0);
+        }
+    }
+
+    #[test]
+    fn rejects_coinbase_nonce_other_than_block_index() {
+        for ledger in [LedgerMode::Account, LedgerMode::Utxo] {
+            let mut state = state(ledger);
+            let mut coinbase = Transaction::coinbase(1, &miner(), SUBSIDY, ledger);
+            coinbase.nonce = 2;
+            let parent = Genesis::default().block();
+            let block = testing::block(&parent, &[coinbase], 0);
+            assert_eq!(
+                state.apply_block(&block, SUBSIDY).unwrap_err(),
+                "coinbase nonce 2 does not match the block index 1"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_coinbase_recreating_unspent_outputs() {
+        let mut state = state(LedgerMode::Utxo);
+        let block = coinbase_block(1, SUBSIDY, LedgerMode::Utxo);
+        state.apply_block(&block, SUBSIDY).unwrap();
+
+        let coinbase = &block.transactions[0];
+        assert_eq!(
+            state.apply_block(&block, SUBSIDY).unwrap_err(),
+            format!("output {}:0 already exists", coinbase.id())
+        );
+        assert_eq!(state.get_account(&miner()).balance, SUBSIDY);
+    }
+}
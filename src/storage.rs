@@ -0,0 +1,434 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::Path;
+
+use crate::chain::Block;
+use crate::hash::BlockHash;
+use crate::wal::{Mutation, WriteAheadLog};
+
+/// Storage of the canonical chain, from genesis at index 0 up to the tip.
+pub trait BlockStore: Debug + Send {
+    fn get_by_index(&self, index: usize) -> Option<Block>;
+    fn get_by_hash(&self, hash: &BlockHash) -> Option<Block>;
+    /// Number of stored blocks, which is also the index of the next block.
+    fn height(&self) -> usize;
+    /// Appends `block` on top of the stored chain.
+    fn append(&mut self, block: Block) -> io::Result<()>;
+    /// Drops every stored block with an index of `height` or above.
+    fn truncate(&mut self, height: usize) -> io::Result<()>;
+
+    /// Replaces every block from index `fork` upwards with `connected`.
+    fn apply(&mut self, fork: usize, connected: &[Block]) -> io::Result<()> {
+        self.truncate(fork)?;
+        for block in connected {
+            self.append(block.clone())?;
+        }
+        Ok(())
+    }
+
+    fn tip(&self) -> Option<Block> {
+        self.height()
+            .checked_sub(1)
+            .and_then(|index| self.get_by_index(index))
+    }
+
+    fn blocks(&self, range: Range<usize>) -> Vec<Block> {
+        range.map_while(|index| self.get_by_index(index)).collect()
+    }
+}
+
+/// Keeps the chain in memory only; everything is lost on restart.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    blocks: Vec<Block>,
+    indexes: HashMap<BlockHash, usize>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl BlockStore for MemoryStore {
+    fn get_by_index(&self, index: usize) -> Option<Block> {
+        self.blocks.get(index).cloned()
+    }
+
+    fn get_by_hash(&self, hash: &BlockHash) -> Option<Block> {
+        self.indexes
+            .get(hash)
+            .and_then(|index| self.get_by_index(*index))
+    }
+
+    fn height(&self) -> usize {
+        self.blocks.len()
+    }
+
+    fn append(&mut self, block: Block) -> io::Result<()> {
+        self.indexes.insert(block.header.hash, self.blocks.len());
+        self.blocks.push(block);
+        Ok(())
+    }
+
+    fn truncate(&mut self, height: usize) -> io::Result<()> {
+        for block in self.blocks.drain(height.min(self.blocks.len())..) {
+            self.indexes.remove(&block.header.hash);
+        }
+        Ok(())
+    }
+}
+
+/// Append-only file of bincode encoded blocks, each record prefixed with its
+/// length as a little endian u32. The file starts with [`FILE_MAGIC`] and the
+/// format version as a little endian u32.
+///
+/// The byte offset and hash of every block are indexed in memory when the
+/// file is opened; blocks themselves are read from disk on demand. Chain
+/// mutations go through a [`WriteAheadLog`] so that an interrupted write is
+/// completed on the next start.
+#[derive(Debug)]
+pub struct FileStore {
+    file: File,
+    wal: WriteAheadLog,
+    offsets: Vec<u64>,
+    indexes: HashMap<BlockHash, usize>,
+}
+
+/// Marks the start of a block file.
+const FILE_MAGIC: &[u8; 4] = b"NCBK";
+/// Version of the block file format, bumped whenever records change.
+const FILE_VERSION: u32 = 1;
+const FILE_HEADER_LEN: u64 = 8;
+
+impl FileStore {
+    pub fn open(data_dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(data_dir)?;
+        let path = data_dir.join("blocks.dat");
+        let wal = WriteAheadLog::open(data_dir)?;
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        let mut store = FileStore {
+            file,
+            wal,
+            offsets: vec![],
+            indexes: HashMap::new(),
+        };
+        store.check_header()?;
+        store.build_index()?;
+        if let Some(mutation) = store.wal.recover()? {
+            if mutation.fork <= store.height() {
+                log::warn!(
+                    "completing interrupted chain write at height {}",
+                    mutation.fork
+                );
+                store.write_mutation(&mutation)?;
+            } else {
+                log::error!(
+                    "write-ahead log starts at height {} above the stored chain, dropping it",
+                    mutation.fork
+                );
+            }
+            store.wal.commit()?;
+        }
+        Ok(store)
+    }
+
+    fn write_mutation(&mut self, mutation: &Mutation) -> io::Result<()> {
+        self.truncate(mutation.fork)?;
+        for block in &mutation.connected {
+            self.append(block.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Writes the file header to a new block file, or checks the one of an
+    /// existing file.
+    fn check_header(&mut self) -> io::Result<()> {
+        if self.file.metadata()?.len() == 0 {
+            self.file.write_all(&file_header())?;
+            return self.file.sync_data();
+        }
+        let mut header = [0u8; FILE_HEADER_LEN as usize];
+        let mut reader = &self.file;
+        reader.seek(SeekFrom::Start(0))?;
+        reader
+            .read_exact(&mut header)
+            .map_err(|_| unknown_format())?;
+        if &header[..4] != FILE_MAGIC {
+            return Err(unknown_format());
+        }
+        let version = u32::from_le_bytes(header[4..].try_into().unwrap());
+        if version != FILE_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("block file has format version {version}, we only read {FILE_VERSION}"),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Scans the block file, dropping a trailing record left by a torn write.
+    /// Any other unreadable record is an error, as the blocks after it would
+    /// be lost.
+    fn build_index(&mut self) -> io::Result<()> {
+        let len = self.file.metadata()?.len();
+        let mut reader = BufReader::new(self.file.try_clone()?);
+        reader.seek(SeekFrom::Start(FILE_HEADER_LEN))?;
+        let mut offset = FILE_HEADER_LEN;
+        while offset < len {
+            let mut record_len = [0u8; 4];
+            let torn = if len - offset < 4 {
+                true
+            } else {
+                reader.read_exact(&mut record_len)?;
+                offset + 4 + u32::from_le_bytes(record_len) as u64 > len
+            };
+            if torn {
+                log::warn!("dropping torn block record at offset {offset}");
+                self.file.set_len(offset)?;
+                self.file.sync_data()?;
+                break;
+            }
+            let block = read_payload(&mut reader, u32::from_le_bytes(record_len)).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("block record at offset {offset} is corrupt: {e}"),
+                )
+            })?;
+            self.indexes.insert(block.header.hash, self.offsets.len());
+            self.offsets.push(offset);
+            offset += 4 + u32::from_le_bytes(record_len) as u64;
+        }
+        Ok(())
+    }
+
+    fn read_block(&self, offset: u64) -> io::Result<Block> {
+        let mut reader = BufReader::new(&self.file);
+        reader.seek(SeekFrom::Start(offset))?;
+        Ok(read_record(&mut reader)?.0)
+    }
+}
+
+impl BlockStore for FileStore {
+    fn get_by_index(&self, index: usize) -> Option<Block> {
+        let offset = *self.offsets.get(index)?;
+        match self.read_block(offset) {
+            Ok(block) => Some(block),
+            Err(e) => {
+                log::error!("failed to read block {index}: {e}");
+                None
+            }
+        }
+    }
+
+    fn get_by_hash(&self, hash: &BlockHash) -> Option<Block> {
+        self.get_by_index(*self.indexes.get(hash)?)
+    }
+
+    fn height(&self) -> usize {
+        self.offsets.len()
+    }
+
+    fn append(&mut self, block: Block) -> io::Result<()> {
+        let offset = self.file.metadata()?.len();
+        self.file.write_all(&encode_record(&block)?)?;
+        self.file.sync_data()?;
+        self.indexes.insert(block.header.hash, self.offsets.len());
+        self.offsets.push(offset);
+        Ok(())
+    }
+
+    fn truncate(&mut self, height: usize) -> io::Result<()> {
+        if let Some(offset) = self.offsets.get(height) {
+            self.file.set_len(*offset)?;
+            self.file.sync_data()?;
+            self.offsets.truncate(height);
+            self.indexes.retain(|_, index| *index < height);
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, fork: usize, connected: &[Block]) -> io::Result<()> {
+        let mutation = Mutation {
+            fork,
+            connected: connected.to_vec(),
+        };
+        self.wal.begin(&mutation)?;
+        self.write_mutation(&mutation)?;
+        self.wal.commit()
+    }
+}
+
+fn file_header() -> Vec<u8> {
+    let mut header = FILE_MAGIC.to_vec();
+    header.extend_from_slice(&FILE_VERSION.to_le_bytes());
+    header
+}
+
+fn unknown_format() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "block file has no known format header",
+    )
+}
+
+fn encode_record(block: &Block) -> io::Result<Vec<u8>> {
+    let payload = bincode::serialize(block).map_err(io::Error::other)?;
+    let mut record = Vec::with_capacity(4 + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&payload);
+    Ok(record)
+}
+
+/// Reads one length-prefixed block record, returning the block and the number
+/// of bytes the record takes.
+fn read_record(reader: &mut impl Read) -> io::Result<(Block, u64)> {
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len);
+    Ok((read_payload(reader, len)?, 4 + len as u64))
+}
+
+fn read_payload(reader: &mut impl Read, len: u32) -> io::Result<Block> {
+    // Read through `take` rather than into a buffer of `len` bytes so that a
+    // corrupt length does not trigger a huge allocation.
+    let mut payload = vec![];
+    reader.take(len as u64).read_to_end(&mut payload)?;
+    if payload.len() != len as usize {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    bincode::deserialize(&payload).map_err(io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::genesis::Genesis;
+    use crate::testing::{branch, data_dir};
+
+    /// Stores a chain of height 3 and logs, without applying it, a mutation
+    /// replacing its last block with three others. Returns the bytes of the
+    /// block file and of the log, and the block file once the mutation is
+    /// applied.
+    fn interrupted_mutation(dir: &Path) -> (Vec<u8>, Vec<u8>, Vec<u8>, Mutation) {
+        let genesis = Genesis::default().block();
+        let mut store = FileStore::open(dir).unwrap();
+        store.append(genesis.clone()).unwrap();
+        for block in branch(&genesis, 2, 0) {
+            store.append(block).unwrap();
+        }
+        let mutation = Mutation {
+            fork: 2,
+            connected: branch(&store.get_by_index(1).unwrap(), 3, 1),
+        };
+        let blocks = fs::read(dir.join("blocks.dat")).unwrap();
+        store.apply(mutation.fork, &mutation.connected).unwrap();
+        let applied = fs::read(dir.join("blocks.dat")).unwrap();
+        drop(store);
+        WriteAheadLog::open(dir).unwrap().begin(&mutation).unwrap();
+        let wal = fs::read(dir.join("wal.log")).unwrap();
+        (blocks, wal, applied, mutation)
+    }
+
+    fn reopen(dir: &Path, blocks: &[u8], wal: &[u8]) -> FileStore {
+        fs::write(dir.join("blocks.dat"), blocks).unwrap();
+        fs::write(dir.join("wal.log"), wal).unwrap();
+        FileStore::open(dir).unwrap()
+    }
+
+    #[test]
+    fn rolls_back_torn_log_record() {
+        let dir = data_dir("torn-wal");
+        let (blocks, wal, _, mutation) = interrupted_mutation(&dir);
+        let tip = reopen(&dir, &blocks, &[]).tip().unwrap();
+        for cut in [1, 8, 39, 40, wal.len() - 1] {
+            let store = reopen(&dir, &blocks, &wal[..cut]);
+            assert_eq!(store.height(), 3, "log cut at {cut}");
+            assert_eq!(store.tip(), Some(tip.clone()), "log cut at {cut}");
+            assert_eq!(fs::metadata(dir.join("wal.log")).unwrap().len(), 0);
+        }
+        let store = reopen(&dir, &blocks, &wal);
+        assert_eq!(store.height(), 5);
+        assert_eq!(store.tip().as_ref(), mutation.connected.last());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn completes_interrupted_block_writes() {
+        let dir = data_dir("interrupted-write");
+        let (blocks, wal, applied, mutation) = interrupted_mutation(&dir);
+        let fork_offset = reopen(&dir, &blocks, &[]).offsets[mutation.fork] as usize;
+        let mut cuts = vec![fork_offset, fork_offset + 1, fork_offset + 4];
+        cuts.extend((fork_offset..applied.len()).step_by(37));
+        cuts.push(applied.len() - 1);
+        for cut in cuts {
+            let store = reopen(&dir, &applied[..cut], &wal);
+            assert_eq!(store.height(), 5, "block file cut at {cut}");
+            assert_eq!(
+                store.tip().as_ref(),
+                mutation.connected.last(),
+                "block file cut at {cut}"
+            );
+            assert_eq!(fs::read(dir.join("blocks.dat")).unwrap(), applied);
+        }
+        // The log may also be written before the block file is touched.
+        let store = reopen(&dir, &blocks, &wal);
+        assert_eq!(store.tip().as_ref(), mutation.connected.last());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn drops_torn_trailing_block_record() {
+        let dir = data_dir("torn-block");
+        let (blocks, _, _, _) = interrupted_mutation(&dir);
+        let store = reopen(&dir, &blocks, &[]);
+        let last_offset = store.offsets[2] as usize;
+        let tip = store.get_by_index(1).unwrap();
+        for cut in [last_offset + 1, last_offset + 4, blocks.len() - 1] {
+            let store = reopen(&dir, &blocks[..cut], &[]);
+            assert_eq!(store.height(), 2, "block file cut at {cut}");
+            assert_eq!(store.tip(), Some(tip.clone()), "block file cut at {cut}");
+            assert_eq!(
+                fs::metadata(dir.join("blocks.dat")).unwrap().len(),
+                last_offset as u64
+            );
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_corrupt_block_record() {
+        let dir = data_dir("corrupt-block");
+        let (mut blocks, _, _, _) = interrupted_mutation(&dir);
+        let offset = reopen(&dir, &blocks, &[]).offsets[1] as usize;
+        blocks[offset..offset + 4].copy_from_slice(&3u32.to_le_bytes());
+        fs::write(dir.join("blocks.dat"), &blocks).unwrap();
+        assert!(FileStore::open(&dir).is_err());
+        assert_eq!(fs::read(dir.join("blocks.dat")).unwrap(), blocks);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn drops_log_record_above_stored_chain() {
+        let dir = data_dir("wal-above-chain");
+        let (blocks, _, _, _) = interrupted_mutation(&dir);
+        let genesis = Genesis::default().block();
+        let mutation = Mutation {
+            fork: 4,
+            connected: branch(&genesis, 5, 2).split_off(3),
+        };
+        WriteAheadLog::open(&dir).unwrap().begin(&mutation).unwrap();
+        let wal = fs::read(dir.join("wal.log")).unwrap();
+        let store = reopen(&dir, &blocks, &wal);
+        assert_eq!(store.height(), 3);
+        assert_eq!(fs::metadata(dir.join("wal.log")).unwrap().len(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
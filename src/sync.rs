@@ -0,0 +1,452 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::{
+    chain::{Block, BlockBody, BlockHeader, BlockStatus},
+    hash::BlockHash,
+    net::{P2PMessage, Route},
+    ApiState,
+};
+
+/// Maximum number of headers sent in answer to one request.
+pub const MAX_HEADERS: usize = 512;
+/// Maximum number of bodies sent in answer to one request.
+pub const MAX_BODIES: usize = 16;
+/// Maximum number of validated headers waiting for their bodies, which bounds
+/// the memory a sync takes.
+const MAX_PENDING_HEADERS: usize = 4 * MAX_HEADERS;
+/// Time after which a request left unanswered is given up on.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How a node catches up with peers whose chain is ahead of ours.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum SyncMode {
+    /// Ask for the whole chain of the peer, blocks included, in one message.
+    #[value(name = "full")]
+    Full,
+    /// Download and validate the header chain first, then fetch the bodies
+    /// of its blocks from every peer in parallel.
+    #[default]
+    #[value(name = "headers")]
+    Headers,
+}
+
+#[derive(Debug)]
+struct HeaderRequest {
+    id: u64,
+    peer: String,
+    start: usize,
+    count: usize,
+    sent: Instant,
+}
+
+#[derive(Debug)]
+struct BodyRequest {
+    peer: String,
+    hashes: Vec<BlockHash>,
+    sent: Instant,
+}
+
+/// Headers-first sync: headers are requested from one peer by range and
+/// validated as a chain, then the bodies they commit to are requested by
+/// hash from every connected peer, one batch per peer at a time. Blocks are
+/// added to our chain in order as their bodies arrive.
+#[derive(Debug)]
+pub struct HeaderSync {
+    local_peer_id: String,
+    next_request_id: u64,
+    /// Peers that completed the handshake.
+    peers: Vec<String>,
+    /// Peers that failed to send a body we asked them for, which are not
+    /// asked again until the sync is over.
+    lacking: HashSet<String>,
+    header_request: Option<HeaderRequest>,
+    /// Peer whose chain goes on after the last pending header, to continue
+    /// the header download with once fewer headers are pending.
+    more_headers: Option<String>,
+    /// Validated headers of the branch being downloaded, in chain order. The
+    /// first one always extends a block of our tree.
+    headers: VecDeque<BlockHeader>,
+    /// Blocks downloaded ahead of the blocks before them.
+    blocks: HashMap<BlockHash, Block>,
+    body_requests: HashMap<u64, BodyRequest>,
+}
+
+impl HeaderSync {
+    pub fn new(local_peer_id: String) -> Self {
+        HeaderSync {
+            local_peer_id,
+            next_request_id: 0,
+            peers: vec![],
+            lacking: HashSet::new(),
+            header_request: None,
+            more_headers: None,
+            headers: VecDeque::new(),
+            blocks: HashMap::new(),
+            body_requests: HashMap::new(),
+        }
+    }
+
+    /// Registers a peer we completed the handshake with and syncs with it
+    /// unless a sync is running already.
+    pub fn add_peer(&mut self, shared_states: &ApiState, peer: String) {
+        if !self.peers.contains(&peer) {
+            self.peers.push(peer.clone());
+        }
+        self.start(shared_states, Some(peer));
+    }
+
+    /// Asks `peer`, or any peer, for the headers following our tip unless a
+    /// sync is running already.
+    pub fn start(&mut self, shared_states: &ApiState, peer: Option<String>) {
+        if self.header_request.is_some() || !self.headers.is_empty() {
+            return;
+        }
+        let Some(peer) = peer.or_else(|| self.peers.first().cloned()) else {
+            log::info!("no peer to sync with");
+            return;
+        };
+        // Starting at our tip shows whether the peer extends it. If the peer
+        // is on a fork, the answer tells us to look further back.
+        let start = shared_states.chains.lock().unwrap().next_index() - 1;
+        self.request_headers(shared_states, peer, start.max(1));
+    }
+
+    fn route(&mut self, peer: &str) -> Route {
+        self.next_request_id += 1;
+        Route {
+            id: self.next_request_id,
+            from: self.local_peer_id.clone(),
+            to: peer.to_string(),
+        }
+    }
+
+    fn send(shared_states: &ApiState, msg: P2PMessage) {
+        if let Err(e) = shared_states.transmit_handlers.swarm_tx.send(msg) {
+            log::error!("error is {e}");
+        }
+    }
+
+    fn request_headers(&mut self, shared_states: &ApiState, peer: String, start: usize) {
+        // Fewer headers are asked for when their seals are costly to verify.
+        let count = shared_states
+            .chains
+            .lock()
+            .unwrap()
+            .params
+            .pow()
+            .max_verified_headers()
+            .min(MAX_HEADERS);
+        log::info!("requesting {count} headers from {start} from {peer}");
+        let route = self.route(&peer);
+        self.header_request = Some(HeaderRequest {
+            id: route.id,
+            peer,
+            start,
+            count,
+            sent: Instant::now(),
+        });
+        Self::send(
+            shared_states,
+            P2PMessage::GetHeaders {
+                route,
+                start,
+                count,
+            },
+        );
+    }
+
+    /// Spreads the bodies not requested yet among the peers without an
+    /// outstanding request, a batch per peer.
+    fn request_bodies(&mut self, shared_states: &ApiState) {
+        let requested: HashSet<BlockHash> = self
+            .body_requests
+            .values()
+            .flat_map(|request| request.hashes.iter().copied())
+            .collect();
+        let missing: Vec<BlockHash> = self
+            .headers
+            .iter()
+            .map(|header| header.hash)
+            .filter(|hash| !self.blocks.contains_key(hash) && !requested.contains(hash))
+            .collect();
+        let idle: Vec<String> = self
+            .peers
+            .iter()
+            .filter(|peer| {
+                !self.lacking.contains(*peer)
+                    && !self
+                        .body_requests
+                        .values()
+                        .any(|request| request.peer == **peer)
+            })
+            .cloned()
+            .collect();
+        for (peer, hashes) in idle.into_iter().zip(missing.chunks(MAX_BODIES)) {
+            log::info!("requesting {} bodies from {peer}", hashes.len());
+            let route = self.route(&peer);
+            self.body_requests.insert(
+                route.id,
+                BodyRequest {
+                    peer,
+                    hashes: hashes.to_vec(),
+                    sent: Instant::now(),
+                },
+            );
+            Self::send(
+                shared_states,
+                P2PMessage::GetBodies {
+                    route,
+                    hashes: hashes.to_vec(),
+                },
+            );
+        }
+        if self.body_requests.is_empty() && !self.headers.is_empty() {
+            log::warn!("no peer left to download bodies from, giving up the sync");
+            self.reset();
+        }
+    }
+
+    /// Validates the headers a peer sent us and queues their bodies for
+    /// download. Headers beyond the number we asked for are ignored, as are
+    /// answers authored by another peer than the one we asked, `source`.
+    pub fn on_headers(
+        &mut self,
+        shared_states: &ApiState,
+        source: Option<String>,
+        route: Route,
+        mut headers: Vec<BlockHeader>,
+    ) {
+        let Some(request) = self
+            .header_request
+            .take_if(|request| request.id == route.id && source.as_ref() == Some(&request.peer))
+        else {
+            return;
+        };
+        headers.truncate(request.count);
+        let more = headers.len() >= request.count;
+        let chains = shared_states.chains.lock().unwrap();
+        let new: Vec<BlockHeader> = headers
+            .into_iter()
+            .skip_while(|header| chains.knows(&header.hash))
+            .collect();
+        let Some(first) = new.first() else {
+            drop(chains);
+            if more {
+                let start = request.start + request.count;
+                self.request_headers(shared_states, request.peer, start);
+            } else {
+                log::info!("in sync with {}", request.peer);
+            }
+            return;
+        };
+        let parent = first.previous_hash;
+        let extends_pending = self
+            .headers
+            .back()
+            .is_some_and(|header| header.hash == parent);
+        if !extends_pending && !self.headers.is_empty() {
+            log::warn!("{} switched branch while we synced with it", request.peer);
+            drop(chains);
+            self.reset();
+            return;
+        }
+        if !extends_pending && !chains.knows(&parent) {
+            drop(chains);
+            if request.start > 1 {
+                // The branch of the peer forks off ours below `start`.
+                let start = request.start.saturating_sub(request.count).max(1);
+                self.request_headers(shared_states, request.peer, start);
+            } else {
+                log::warn!("headers from {} do not extend our chain", request.peer);
+            }
+            return;
+        }
+
+        let validated = self.headers.len();
+        let branch: Vec<BlockHeader> = self.headers.iter().cloned().chain(new).collect();
+        let work = match chains.check_headers(&branch, validated) {
+            Ok(work) => work,
+            Err(e) => {
+                log::warn!("{} sent an invalid header chain: {e}", request.peer);
+                drop(chains);
+                self.ban_peer(shared_states, &request.peer);
+                self.reset();
+                return;
+            }
+        };
+        if !more && work <= chains.cumulative_work() {
+            log::info!("chain of {} is not heavier than ours", request.peer);
+            drop(chains);
+            self.reset();
+            return;
+        }
+        drop(chains);
+        log::info!(
+            "validated headers up to {}, downloading {} bodies",
+            branch.last().unwrap().index,
+            branch.len()
+        );
+        self.headers = branch.into();
+        if more {
+            self.more_headers = Some(request.peer);
+            self.continue_headers(shared_states);
+        }
+        self.request_bodies(shared_states);
+    }
+
+    /// Requests the next headers if we are not too far ahead of the bodies.
+    fn continue_headers(&mut self, shared_states: &ApiState) {
+        if self.header_request.is_some() || self.headers.len() >= MAX_PENDING_HEADERS {
+            return;
+        }
+        let (Some(peer), Some(last)) = (self.more_headers.take(), self.headers.back()) else {
+            return;
+        };
+        let start = last.index + 1;
+        self.request_headers(shared_states, peer, start);
+    }
+
+    /// Keeps the bodies a peer sent us that match the headers they were
+    /// requested for, and adds the blocks that follow on from our chain.
+    /// Answers authored by another peer than the one we asked are ignored.
+    pub fn on_bodies(
+        &mut self,
+        shared_states: &ApiState,
+        source: Option<String>,
+        route: Route,
+        bodies: Vec<BlockBody>,
+    ) {
+        let asked = self
+            .body_requests
+            .get(&route.id)
+            .is_some_and(|request| source.as_ref() == Some(&request.peer));
+        if !asked {
+            return;
+        }
+        let request = self.body_requests.remove(&route.id).unwrap();
+        for body in bodies {
+            if !request.hashes.contains(&body.hash) {
+                continue;
+            }
+            let Some(header) = self.headers.iter().find(|header| header.hash == body.hash) else {
+                continue;
+            };
+            match Block::from_parts(header.clone(), body) {
+                Ok(block) => {
+                    self.blocks.insert(block.header.hash, block);
+                }
+                Err(e) => log::warn!("{} sent an invalid body: {e}", request.peer),
+            }
+        }
+        let pending: HashSet<BlockHash> = self.headers.iter().map(|header| header.hash).collect();
+        if request
+            .hashes
+            .iter()
+            .any(|hash| pending.contains(hash) && !self.blocks.contains_key(hash))
+        {
+            log::info!("{} does not have every body we asked for", request.peer);
+            self.lacking.insert(request.peer);
+        }
+        self.connect_blocks(shared_states);
+        self.continue_headers(shared_states);
+        self.request_bodies(shared_states);
+    }
+
+    /// Adds the downloaded blocks following on from our block tree to the
+    /// chain, in order. Their headers were validated already, so only their
+    /// bodies are checked.
+    fn connect_blocks(&mut self, shared_states: &ApiState) {
+        let mut chains = shared_states.chains.lock().unwrap();
+        let mut new_tip = false;
+        while let Some(block) = self
+            .headers
+            .front()
+            .and_then(|header| self.blocks.remove(&header.hash))
+        {
+            self.headers.pop_front();
+            match chains.add_block_with_checked_header(block) {
+                BlockStatus::NewTip(reorg) => {
+                    shared_states.on_new_tip(&chains, &reorg);
+                    new_tip = true;
+                }
+                BlockStatus::Invalid(e) => {
+                    log::warn!("downloaded block is invalid: {e}");
+                    self.reset();
+                    break;
+                }
+                BlockStatus::Fatal(e) => {
+                    drop(chains);
+                    crate::shut_down(&e);
+                }
+                _ => {}
+            }
+        }
+        if new_tip {
+            let latest_block = chains.get_latest_block().unwrap();
+            log::info!("synced up to block {}", latest_block.header.index);
+            Self::send(
+                shared_states,
+                P2PMessage::ResponseBlockchain(vec![latest_block]),
+            );
+        }
+        if self.headers.is_empty() && self.more_headers.is_none() {
+            self.lacking.clear();
+        }
+    }
+
+    /// Gives up on the requests left unanswered for too long, forgetting the
+    /// peers they were sent to, and sends what they asked for to other peers.
+    pub fn tick(&mut self, shared_states: &ApiState) {
+        if let Some(request) = self
+            .header_request
+            .take_if(|request| request.sent.elapsed() > REQUEST_TIMEOUT)
+        {
+            log::warn!("{} did not send the headers we asked for", request.peer);
+            self.remove_peer(&request.peer);
+            if self.headers.is_empty() {
+                self.start(shared_states, None);
+            } else {
+                self.more_headers = self.peers.first().cloned();
+                self.continue_headers(shared_states);
+            }
+        }
+        let expired: Vec<u64> = self
+            .body_requests
+            .iter()
+            .filter(|(_, request)| request.sent.elapsed() > REQUEST_TIMEOUT)
+            .map(|(id, _)| *id)
+            .collect();
+        if expired.is_empty() {
+            return;
+        }
+        for id in expired {
+            let request = self.body_requests.remove(&id).unwrap();
+            log::warn!("{} did not send the bodies we asked for", request.peer);
+            self.remove_peer(&request.peer);
+        }
+        self.request_bodies(shared_states);
+    }
+
+    /// Forgets a peer until its next handshake.
+    fn remove_peer(&mut self, peer: &str) {
+        self.peers.retain(|known| known != peer);
+    }
+
+    /// Stops syncing with `peer` and disconnects it, for sending us invalid
+    /// blocks or headers.
+    pub fn ban_peer(&mut self, shared_states: &ApiState, peer: &str) {
+        self.remove_peer(peer);
+        Self::send(shared_states, P2PMessage::BanPeer(peer.to_string()));
+    }
+
+    /// Drops every request, header and block of the current sync.
+    fn reset(&mut self) {
+        self.header_request = None;
+        self.more_headers = None;
+        self.headers.clear();
+        self.blocks.clear();
+        self.body_requests.clear();
+        self.lacking.clear();
+    }
+}
@@ -0,0 +1,78 @@
+//! Helpers shared by the unit tests of every module.
+
+use std::fs;
+use std::path::PathBuf;
+
+use crate::chain::{Block, Chain, ChainParams, DEFAULT_MAX_FUTURE_DRIFT};
+use crate::consensus::{self, ConsensusMode};
+use crate::genesis::Genesis;
+use crate::hash::HashAlgorithm;
+use crate::state::LedgerMode;
+use crate::storage::MemoryStore;
+use crate::transaction::Transaction;
+
+/// Empty directory named after the calling test, unique to this process.
+pub fn data_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("naivechain-{name}-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// In-memory chain without proof of work, keeping its ledger in `ledger` mode.
+pub fn chain(ledger: LedgerMode) -> Chain {
+    chain_with(ChainParams {
+        consensus: ConsensusMode::Naive,
+        ledger,
+        ..ChainParams::default()
+    })
+}
+
+/// In-memory chain following `params` from a default genesis.
+pub fn chain_with(params: ChainParams) -> Chain {
+    let genesis = Genesis {
+        params: params.clone(),
+        ..Genesis::default()
+    };
+    let consensus = consensus::from_params(&params);
+    Chain::new(
+        genesis.block(),
+        params,
+        consensus,
+        Box::new(MemoryStore::new()),
+        DEFAULT_MAX_FUTURE_DRIFT,
+    )
+    .unwrap()
+}
+
+/// Block holding `transactions` on top of `parent`, one second later.
+pub fn block(parent: &Block, transactions: &[Transaction], seed: u64) -> Block {
+    Block::new(
+        HashAlgorithm::Sha256,
+        parent.header.index + 1,
+        parent.header.hash,
+        parent.header.timestamp + 1,
+        transactions,
+        0,
+        seed,
+    )
+}
+
+/// Block on top of `parent` paying the block subsidy of `chain` to `miner`.
+pub fn coinbase_block(chain: &Chain, parent: &Block, miner: &str, seed: u64) -> Block {
+    let index = parent.header.index + 1;
+    let reward = chain.params.block_subsidy(index);
+    let coinbase = Transaction::coinbase(index, miner, reward, chain.params.ledger);
+    block(parent, &[coinbase], seed)
+}
+
+/// Builds `count` empty blocks on top of `parent`, one second apart and told
+/// apart from other branches by `seed`.
+pub fn branch(parent: &Block, count: usize, seed: u64) -> Vec<Block> {
+    let mut blocks: Vec<Block> = vec![];
+    for _ in 0..count {
+        let previous = blocks.last().unwrap_or(parent);
+        blocks.push(block(previous, &[], seed));
+    }
+    blocks
+}
@@ -0,0 +1,180 @@
+use libp2p::identity::ed25519::{Keypair, PublicKey, SecretKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::state::LedgerMode;
+use crate::utxo::{OutPoint, TxOutput};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Transaction {
+    /// Hex encoded ed25519 public key of the author.
+    pub sender: String,
+    pub payload: String,
+    /// Hex encoded public key of the account credited with `amount`.
+    #[serde(default)]
+    pub recipient: Option<String>,
+    #[serde(default)]
+    pub amount: u64,
+    /// Paid to the miner on top of `amount`, in account mode. In UTXO mode
+    /// the fee is whatever the outputs leave of the inputs.
+    #[serde(default)]
+    pub fee: u64,
+    /// Outputs of `sender` spent by this transaction, in UTXO mode.
+    #[serde(default)]
+    pub inputs: Vec<OutPoint>,
+    /// Outputs created by this transaction, in UTXO mode.
+    #[serde(default)]
+    pub outputs: Vec<TxOutput>,
+    /// In account mode, must equal the number of transactions previously
+    /// sent by `sender`. Coinbase transactions carry the block index.
+    pub nonce: u64,
+    /// Hex encoded ed25519 signature over [`Transaction::signing_bytes`].
+    pub signature: String,
+}
+
+impl Transaction {
+    /// Unsigned transaction paying `reward` to `miner_address`, placed first
+    /// in the block at `index`.
+    pub fn coinbase(index: usize, miner_address: &str, reward: u64, ledger: LedgerMode) -> Self {
+        let mut coinbase = Transaction {
+            sender: String::new(),
+            payload: String::new(),
+            recipient: None,
+            amount: 0,
+            fee: 0,
+            inputs: vec![],
+            outputs: vec![],
+            nonce: index as u64,
+            signature: String::new(),
+        };
+        match ledger {
+            LedgerMode::Account => {
+                coinbase.recipient = Some(miner_address.to_string());
+                coinbase.amount = reward;
+            }
+            LedgerMode::Utxo => coinbase.outputs.push(TxOutput {
+                address: miner_address.to_string(),
+                amount: reward,
+            }),
+        }
+        coinbase
+    }
+
+    /// Coinbase transactions are the only ones without a sender.
+    pub fn is_coinbase(&self) -> bool {
+        self.sender.is_empty()
+    }
+
+    /// Fills in `sender` and `signature` using the hex encoded ed25519 `secret_key`.
+    pub fn sign(mut self, secret_key: &str) -> Result<Self, String> {
+        let keypair = keypair_from_hex(secret_key)?;
+        self.sender = hex::encode(keypair.public().to_bytes());
+        self.signature = hex::encode(keypair.sign(&self.signing_bytes()));
+        Ok(self)
+    }
+
+    /// Bytes covered by the signature: every field but the signature itself.
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(&(
+            &self.sender,
+            &self.payload,
+            &self.recipient,
+            self.amount,
+            self.fee,
+            &self.inputs,
+            &self.outputs,
+            self.nonce,
+        ))
+        .unwrap()
+    }
+
+    /// Hex encoded SHA-256 of the whole transaction, used as its Merkle leaf.
+    pub fn id(&self) -> String {
+        format!("{:x}", Sha256::digest(serde_json::to_vec(self).unwrap()))
+    }
+
+    pub fn verify_signature(&self) -> bool {
+        verify_hex_signature(&self.sender, &self.signing_bytes(), &self.signature)
+    }
+}
+
+/// Checks the hex encoded ed25519 `signature` of `message` against the hex
+/// encoded `public_key`.
+pub fn verify_hex_signature(public_key: &str, message: &[u8], signature: &str) -> bool {
+    let (Ok(public_key), Ok(signature)) = (hex::decode(public_key), hex::decode(signature)) else {
+        return false;
+    };
+    match PublicKey::try_from_bytes(&public_key) {
+        Ok(public_key) => public_key.verify(message, &signature),
+        Err(_) => false,
+    }
+}
+
+/// Generates a fresh ed25519 key pair, returned as hex encoded
+/// `(public_key, secret_key)`.
+pub fn generate_keypair() -> (String, String) {
+    let keypair = Keypair::generate();
+    let secret = SecretKey::from(keypair.clone());
+    (
+        hex::encode(keypair.public().to_bytes()),
+        hex::encode(secret.as_ref()),
+    )
+}
+
+pub fn keypair_from_hex(secret_key: &str) -> Result<Keypair, String> {
+    let bytes = hex::decode(secret_key).map_err(|e| format!("invalid secret key: {e}"))?;
+    let secret =
+        SecretKey::try_from_bytes(bytes).map_err(|e| format!("invalid secret key: {e}"))?;
+    Ok(Keypair::from(secret))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signed_transfer() -> Transaction {
+        let (_, secret_key) = generate_keypair();
+        Transaction {
+            sender: String::new(),
+            payload: "rent".to_string(),
+            recipient: Some("bb".repeat(32)),
+            amount: 10,
+            fee: 1,
+            inputs: vec![],
+            outputs: vec![],
+            nonce: 0,
+            signature: String::new(),
+        }
+        .sign(&secret_key)
+        .unwrap()
+    }
+
+    #[test]
+    fn verifies_signed_transaction() {
+        assert!(signed_transfer().verify_signature());
+    }
+
+    #[test]
+    fn rejects_tampered_fields() {
+        let mut transaction = signed_transfer();
+        transaction.amount = 1000;
+        assert!(!transaction.verify_signature());
+
+        let mut transaction = signed_transfer();
+        transaction.recipient = Some("cc".repeat(32));
+        assert!(!transaction.verify_signature());
+    }
+
+    #[test]
+    fn rejects_tampered_signature() {
+        let mut transaction = signed_transfer();
+        let mut signature = hex::decode(&transaction.signature).unwrap();
+        signature[0] ^= 1;
+        transaction.signature = hex::encode(signature);
+        assert!(!transaction.verify_signature());
+
+        let mut transaction = signed_transfer();
+        transaction.sender = generate_keypair().0;
+        assert!(!transaction.verify_signature());
+    }
+}
@@ -0,0 +1,277 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::transaction::Transaction;
+
+/// Reference to output `index` of transaction `tx_id`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct OutPoint {
+    pub tx_id: String,
+    pub index: u32,
+}
+
+/// Coins locked to the hex encoded public key `address`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TxOutput {
+    pub address: String,
+    pub amount: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct UnspentOutput {
+    pub out_point: OutPoint,
+    pub output: TxOutput,
+}
+
+/// Previous value of every output created or spent by a transaction.
+pub type UtxoUndo = Vec<(OutPoint, Option<TxOutput>)>;
+
+/// Outputs of the canonical chain that have not been spent yet.
+#[derive(Debug, Clone, Default)]
+pub struct UtxoSet {
+    outputs: HashMap<OutPoint, TxOutput>,
+}
+
+impl UtxoSet {
+    /// Turns the genesis allocations into outputs of the genesis block, one
+    /// per address in lexicographic order.
+    pub fn new(genesis_hash: &str, allocations: &BTreeMap<String, u64>) -> Self {
+        let outputs = allocations
+            .iter()
+            .enumerate()
+            .map(|(index, (address, amount))| {
+                let out_point = OutPoint {
+                    tx_id: genesis_hash.to_string(),
+                    index: index as u32,
+                };
+                let output = TxOutput {
+                    address: address.clone(),
+                    amount: *amount,
+                };
+                (out_point, output)
+            })
+            .collect();
+        UtxoSet { outputs }
+    }
+
+    pub fn unspent(&self, address: &str) -> Vec<UnspentOutput> {
+        let mut unspent: Vec<UnspentOutput> = self
+            .outputs
+            .iter()
+            .filter(|(_, output)| output.address == address)
+            .map(|(out_point, output)| UnspentOutput {
+                out_point: out_point.clone(),
+                output: output.clone(),
+            })
+            .collect();
+        unspent.sort_by(|a, b| a.out_point.cmp(&b.out_point));
+        unspent
+    }
+
+    pub fn balance(&self, address: &str) -> u64 {
+        self.outputs
+            .values()
+            .filter(|output| output.address == address)
+            .map(|output| output.amount)
+            .sum()
+    }
+
+    /// Checks that every input is unspent, owned by the sender and used only
+    /// once, that no output already exists and that the outputs do not
+    /// create coins. Returns the fee left
+    /// over for the miner.
+    pub fn check_transaction(&self, transaction: &Transaction) -> Result<u64, String> {
+        let mut spent = HashSet::new();
+        let mut input_total: u64 = 0;
+        for input in &transaction.inputs {
+            if !spent.insert(input) {
+                return Err(format!(
+                    "output {}:{} is spent twice",
+                    input.tx_id, input.index
+                ));
+            }
+            let Some(output) = self.outputs.get(input) else {
+                return Err(format!(
+                    "output {}:{} is unknown or already spent",
+                    input.tx_id, input.index
+                ));
+            };
+            if output.address != transaction.sender {
+                return Err(format!(
+                    "output {}:{} does not belong to the sender",
+                    input.tx_id, input.index
+                ));
+            }
+            input_total = input_total
+                .checked_add(output.amount)
+                .ok_or("input total overflow")?;
+        }
+        self.new_out_points(transaction)?;
+        let output_total = transaction
+            .outputs
+            .iter()
+            .try_fold(0u64, |total, output| total.checked_add(output.amount))
+            .ok_or("output total overflow")?;
+        input_total
+            .checked_sub(output_total)
+            .ok_or_else(|| "outputs exceed inputs".to_string())
+    }
+
+    /// Spends the inputs of `transaction` and adds its outputs, leaving the
+    /// set untouched on error.
+    pub fn apply_transaction(&mut self, transaction: &Transaction) -> Result<UtxoUndo, String> {
+        self.check_transaction(transaction)?;
+        let mut undo: UtxoUndo = vec![];
+        for input in &transaction.inputs {
+            let previous = self.outputs.remove(input);
+            undo.push((input.clone(), previous));
+        }
+        undo.extend(self.add_outputs(transaction)?);
+        Ok(undo)
+    }
+
+    /// Adds the outputs of `transaction` without spending anything, as done
+    /// for coinbase transactions. Fails without changing the set if any of
+    /// them already exists.
+    pub fn add_outputs(&mut self, transaction: &Transaction) -> Result<UtxoUndo, String> {
+        let out_points = self.new_out_points(transaction)?;
+        let undo = out_points
+            .into_iter()
+            .zip(&transaction.outputs)
+            .map(|(out_point, output)| {
+                let previous = self.outputs.insert(out_point.clone(), output.clone());
+                (out_point, previous)
+            })
+            .collect();
+        Ok(undo)
+    }
+
+    /// Out points of the outputs of `transaction`, none of which may exist
+    /// yet.
+    fn new_out_points(&self, transaction: &Transaction) -> Result<Vec<OutPoint>, String> {
+        let tx_id = transaction.id();
+        (0..transaction.outputs.len() as u32)
+            .map(|index| {
+                let out_point = OutPoint {
+                    tx_id: tx_id.clone(),
+                    index,
+                };
+                if self.outputs.contains_key(&out_point) {
+                    return Err(format!("output {tx_id}:{index} already exists"));
+                }
+                Ok(out_point)
+            })
+            .collect()
+    }
+
+    pub fn revert(&mut self, undo: UtxoUndo) {
+        for (out_point, output) in undo.into_iter().rev() {
+            match output {
+                Some(output) => self.outputs.insert(out_point, output),
+                None => self.outputs.remove(&out_point),
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GENESIS: &str = "genesis";
+
+    fn alice() -> String {
+        "aa".repeat(32)
+    }
+
+    fn bob() -> String {
+        "bb".repeat(32)
+    }
+
+    /// Set holding a single output of 100 owned by alice.
+    fn utxo_set() -> UtxoSet {
+        UtxoSet::new(GENESIS, &[(alice(), 100)].into())
+    }
+
+    fn allocation() -> OutPoint {
+        OutPoint {
+            tx_id: GENESIS.to_string(),
+            index: 0,
+        }
+    }
+
+    fn spend(inputs: Vec<OutPoint>, amount: u64, payload: &str) -> Transaction {
+        Transaction {
+            sender: alice(),
+            payload: payload.to_string(),
+            recipient: None,
+            amount: 0,
+            fee: 0,
+            inputs,
+            outputs: vec![TxOutput {
+                address: bob(),
+                amount,
+            }],
+            nonce: 0,
+            signature: String::new(),
+        }
+    }
+
+    #[test]
+    fn spends_output_and_reverts() {
+        let mut utxos = utxo_set();
+        let transaction = spend(vec![allocation()], 90, "");
+        assert_eq!(utxos.check_transaction(&transaction), Ok(10));
+
+        let undo = utxos.apply_transaction(&transaction).unwrap();
+        assert_eq!(utxos.balance(&alice()), 0);
+        assert_eq!(utxos.balance(&bob()), 90);
+
+        utxos.revert(undo);
+        assert_eq!(utxos.balance(&alice()), 100);
+        assert_eq!(utxos.balance(&bob()), 0);
+        assert_eq!(utxos.unspent(&alice())[0].out_point, allocation());
+    }
+
+    #[test]
+    fn rejects_double_spend() {
+        let mut utxos = utxo_set();
+        utxos
+            .apply_transaction(&spend(vec![allocation()], 100, "first"))
+            .unwrap();
+
+        let second = spend(vec![allocation()], 100, "second");
+        assert_eq!(
+            utxos.apply_transaction(&second),
+            Err("output genesis:0 is unknown or already spent".to_string())
+        );
+        assert_eq!(utxos.balance(&bob()), 100);
+    }
+
+    #[test]
+    fn rejects_input_spent_twice_in_one_transaction() {
+        let utxos = utxo_set();
+        let transaction = spend(vec![allocation(), allocation()], 200, "");
+        assert_eq!(
+            utxos.check_transaction(&transaction),
+            Err("output genesis:0 is spent twice".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_foreign_and_inflating_spends() {
+        let utxos = utxo_set();
+        let mut foreign = spend(vec![allocation()], 100, "");
+        foreign.sender = bob();
+        assert_eq!(
+            utxos.check_transaction(&foreign),
+            Err("output genesis:0 does not belong to the sender".to_string())
+        );
+        let inflating = spend(vec![allocation()], 101, "");
+        assert_eq!(
+            utxos.check_transaction(&inflating),
+            Err("outputs exceed inputs".to_string())
+        );
+    }
+}
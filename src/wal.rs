@@ -0,0 +1,88 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::chain::Block;
+
+/// A change to the stored chain: cut it back to `fork` blocks, then append
+/// `connected`. Appends, chain replacements and reorgs are all expressed this
+/// way, which makes replaying an interrupted mutation idempotent.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Mutation {
+    pub fork: usize,
+    pub connected: Vec<Block>,
+}
+
+/// Single record write-ahead log guarding mutations of a `FileStore`.
+///
+/// A record is laid out as the payload length (u64, little endian), the
+/// SHA-256 of the payload and the bincode encoded [`Mutation`]. The log is
+/// emptied once the mutation has been applied.
+#[derive(Debug)]
+pub struct WriteAheadLog {
+    file: File,
+}
+
+const HEADER_LEN: usize = 8 + 32;
+
+impl WriteAheadLog {
+    pub fn open(data_dir: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(data_dir.join("wal.log"))?;
+        Ok(WriteAheadLog { file })
+    }
+
+    /// Durably records `mutation` before it is applied to the store.
+    pub fn begin(&mut self, mutation: &Mutation) -> io::Result<()> {
+        let payload = bincode::serialize(mutation).map_err(io::Error::other)?;
+        let mut record = Vec::with_capacity(HEADER_LEN + payload.len());
+        record.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+        record.extend_from_slice(&Sha256::digest(&payload));
+        record.extend_from_slice(&payload);
+        self.file.set_len(0)?;
+        (&self.file).seek(SeekFrom::Start(0))?;
+        (&self.file).write_all(&record)?;
+        self.file.sync_data()
+    }
+
+    /// Marks the logged mutation as fully applied.
+    pub fn commit(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_data()
+    }
+
+    /// Returns the mutation that was logged but not committed before the node
+    /// stopped. A torn record means the store was never touched, so it is
+    /// dropped and the mutation is rolled back.
+    pub fn recover(&mut self) -> io::Result<Option<Mutation>> {
+        let mut record = vec![];
+        (&self.file).seek(SeekFrom::Start(0))?;
+        (&self.file).read_to_end(&mut record)?;
+        if record.is_empty() {
+            return Ok(None);
+        }
+        if record.len() >= HEADER_LEN {
+            let (header, payload) = record.split_at(HEADER_LEN);
+            let len = u64::from_le_bytes(header[..8].try_into().unwrap());
+            if len == payload.len() as u64 && header[8..] == Sha256::digest(payload)[..] {
+                let mutation = bincode::deserialize(payload).map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("write-ahead log record cannot be decoded: {e}"),
+                    )
+                })?;
+                return Ok(Some(mutation));
+            }
+        }
+        log::warn!("write-ahead log holds a torn record, rolling it back");
+        self.commit()?;
+        Ok(None)
+    }
+}